@@ -0,0 +1,802 @@
+// TODO: Wave and noise channel output
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use alloc::{vec, vec::Vec};
+use bit_field::BitField;
+
+pub const SAMPLE_RATE: usize = 44_100;
+
+const CPU_CLOCK: usize = 4_194_304;
+
+// the frame sequencer is clocked at 512Hz
+const FRAME_SEQUENCER_CYCLES: usize = CPU_CLOCK / 512;
+
+// keep at most one second of interleaved stereo samples
+const MAX_SAMPLES: usize = SAMPLE_RATE * 2;
+
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+trait Channel {
+    fn read_byte(&self, register: usize) -> u8;
+    fn write_byte(&mut self, register: usize, value: u8);
+
+    fn step(&mut self, _cycles: usize) {}
+
+    fn step_length(&mut self);
+    fn step_envelope(&mut self) {}
+    fn step_sweep(&mut self) {}
+
+    fn enabled(&self) -> bool;
+    fn dac_enabled(&self) -> bool;
+
+    // the current digital output, 0-15
+    fn output(&self) -> u8 {
+        0
+    }
+
+    fn sample(&self) -> f32 {
+        if !self.dac_enabled() {
+            return 0.0;
+        }
+
+        let output = if self.enabled() { self.output() } else { 0 };
+
+        // the DAC maps 0-15 onto 1.0 to -1.0
+        1.0 - f32::from(output) / 7.5
+    }
+}
+
+struct Length {
+    counter: usize,
+    max: usize,
+}
+
+impl Length {
+    fn new(max: usize) -> Self {
+        Length { counter: 0, max }
+    }
+
+    fn load(&mut self, value: usize) {
+        self.counter = self.max - value;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // returns true when the counter expires
+    fn step(&mut self, enabled: bool) -> bool {
+        if enabled && self.counter > 0 {
+            self.counter -= 1;
+
+            return self.counter == 0;
+        }
+
+        false
+    }
+}
+
+struct Square {
+    registers: [u8; 5],
+    has_sweep: bool,
+
+    enabled: bool,
+    length: Length,
+    volume: u8,
+    envelope_timer: u8,
+    frequency_timer: usize,
+    duty_position: usize,
+    sweep_enabled: bool,
+    sweep_timer: u8,
+    shadow_frequency: u16,
+}
+
+impl Square {
+    fn new(has_sweep: bool) -> Self {
+        Square {
+            registers: [0; 5],
+            has_sweep,
+
+            enabled: false,
+            length: Length::new(64),
+            volume: 0,
+            envelope_timer: 0,
+            frequency_timer: 2048 * 4,
+            duty_position: 0,
+            sweep_enabled: false,
+            sweep_timer: 0,
+            shadow_frequency: 0,
+        }
+    }
+}
+
+impl Square {
+    fn frequency(&self) -> u16 {
+        u16::from_le_bytes([self.registers[3], self.registers[4] & 0x07])
+    }
+
+    fn set_frequency(&mut self, frequency: u16) {
+        let bytes = frequency.to_le_bytes();
+
+        self.registers[3] = bytes[0];
+        self.registers[4].set_bits(0..3, bytes[1]);
+    }
+
+    fn period(&self) -> usize {
+        (2048 - usize::from(self.frequency())) * 4
+    }
+
+    fn sweep_period(&self) -> u8 {
+        self.registers[0].get_bits(4..7)
+    }
+
+    fn sweep_shift(&self) -> u8 {
+        self.registers[0].get_bits(0..3)
+    }
+
+    fn envelope_period(&self) -> u8 {
+        self.registers[2].get_bits(0..3)
+    }
+
+    // returns the next sweep frequency, disabling the channel if it overflows
+    fn calculate_sweep(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift();
+
+        let frequency = if self.registers[0].get_bit(3) {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        };
+
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+
+        frequency
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger();
+
+        self.frequency_timer = self.period();
+        self.volume = self.registers[2].get_bits(4..8);
+        self.envelope_timer = self.envelope_period();
+
+        if self.has_sweep {
+            self.shadow_frequency = self.frequency();
+            self.sweep_timer = match self.sweep_period() {
+                0 => 8,
+                period => period,
+            };
+            self.sweep_enabled = self.sweep_period() != 0 || self.sweep_shift() != 0;
+
+            if self.sweep_shift() != 0 {
+                self.calculate_sweep();
+            }
+        }
+    }
+}
+
+impl Channel for Square {
+    fn read_byte(&self, register: usize) -> u8 {
+        // write-only bits read back as 1
+        let mask = match register {
+            0 if self.has_sweep => 0x80,
+            0 => 0xFF,
+            1 => 0x3F,
+            2 => 0x00,
+            3 => 0xFF,
+            4 => 0xBF,
+            _ => unreachable!(),
+        };
+
+        self.registers[register] | mask
+    }
+
+    fn write_byte(&mut self, register: usize, value: u8) {
+        self.registers[register] = value;
+
+        match register {
+            1 => self.length.load(usize::from(value.get_bits(0..6))),
+            2 if !self.dac_enabled() => self.enabled = false,
+            4 if value.get_bit(7) => self.trigger(),
+            _ => {}
+        }
+    }
+
+    fn step(&mut self, cycles: usize) {
+        let mut cycles = cycles;
+
+        while cycles >= self.frequency_timer {
+            cycles -= self.frequency_timer;
+
+            self.frequency_timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+
+        self.frequency_timer -= cycles;
+    }
+
+    fn step_length(&mut self) {
+        if self.length.step(self.registers[4].get_bit(6)) {
+            self.enabled = false;
+        }
+    }
+
+    fn step_envelope(&mut self) {
+        if self.envelope_period() == 0 {
+            return;
+        }
+
+        self.envelope_timer = self.envelope_timer.saturating_sub(1);
+
+        if self.envelope_timer == 0 {
+            self.envelope_timer = self.envelope_period();
+
+            if self.registers[2].get_bit(3) {
+                if self.volume < 15 {
+                    self.volume += 1;
+                }
+            } else if self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn step_sweep(&mut self) {
+        if !self.has_sweep {
+            return;
+        }
+
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+
+        if self.sweep_timer == 0 {
+            self.sweep_timer = match self.sweep_period() {
+                0 => 8,
+                period => period,
+            };
+
+            if self.sweep_enabled && self.sweep_period() != 0 {
+                let frequency = self.calculate_sweep();
+
+                if frequency <= 2047 && self.sweep_shift() != 0 {
+                    self.shadow_frequency = frequency;
+                    self.set_frequency(frequency);
+
+                    // the new frequency is checked for overflow again straight away
+                    self.calculate_sweep();
+                }
+            }
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.registers[2] & 0xF8 != 0
+    }
+
+    fn output(&self) -> u8 {
+        let duty = usize::from(self.registers[1].get_bits(6..8));
+
+        DUTY_CYCLES[duty][self.duty_position] * self.volume
+    }
+}
+
+impl Snapshot for Square {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_bool(self.enabled);
+        state.write_usize(self.length.counter);
+        state.write_u8(self.volume);
+        state.write_u8(self.envelope_timer);
+        state.write_usize(self.frequency_timer);
+        state.write_usize(self.duty_position);
+        state.write_bool(self.sweep_enabled);
+        state.write_u8(self.sweep_timer);
+        state.write_u16(self.shadow_frequency);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.registers)?;
+        self.enabled = state.read_bool()?;
+        self.length.counter = state.read_usize()?;
+        self.volume = state.read_u8()?;
+        self.envelope_timer = state.read_u8()?;
+        self.frequency_timer = state.read_usize()?;
+        self.duty_position = state.read_usize()?;
+        self.sweep_enabled = state.read_bool()?;
+        self.sweep_timer = state.read_u8()?;
+        self.shadow_frequency = state.read_u16()?;
+
+        if self.length.counter > 64
+            || self.volume > 15
+            || self.frequency_timer == 0
+            || self.duty_position > 7
+        {
+            return Err(StateError::InvalidValue);
+        }
+
+        Ok(())
+    }
+}
+
+// stores its registers and runs its length counter but is not yet audible
+struct Wave {
+    registers: [u8; 5],
+    wave_ram: [u8; 16],
+
+    enabled: bool,
+    length: Length,
+}
+
+impl Wave {
+    fn new() -> Self {
+        Wave {
+            registers: [0; 5],
+            wave_ram: [0; 16],
+
+            enabled: false,
+            length: Length::new(256),
+        }
+    }
+}
+
+impl Channel for Wave {
+    fn read_byte(&self, register: usize) -> u8 {
+        let mask = match register {
+            0 => 0x7F,
+            1 => 0xFF,
+            2 => 0x9F,
+            3 => 0xFF,
+            4 => 0xBF,
+            _ => unreachable!(),
+        };
+
+        self.registers[register] | mask
+    }
+
+    fn write_byte(&mut self, register: usize, value: u8) {
+        self.registers[register] = value;
+
+        match register {
+            0 if !self.dac_enabled() => self.enabled = false,
+            1 => self.length.load(usize::from(value)),
+            4 if value.get_bit(7) => {
+                self.enabled = self.dac_enabled();
+                self.length.trigger();
+            }
+            _ => {}
+        }
+    }
+
+    fn step_length(&mut self) {
+        if self.length.step(self.registers[4].get_bit(6)) {
+            self.enabled = false;
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.registers[0].get_bit(7)
+    }
+}
+
+impl Snapshot for Wave {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_bytes(&self.wave_ram);
+        state.write_bool(self.enabled);
+        state.write_usize(self.length.counter);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.registers)?;
+        state.read_bytes(&mut self.wave_ram)?;
+        self.enabled = state.read_bool()?;
+        self.length.counter = state.read_usize()?;
+
+        if self.length.counter > 256 {
+            return Err(StateError::InvalidValue);
+        }
+
+        Ok(())
+    }
+}
+
+// stores its registers and runs its length counter but is not yet audible
+struct Noise {
+    registers: [u8; 5],
+
+    enabled: bool,
+    length: Length,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            registers: [0; 5],
+
+            enabled: false,
+            length: Length::new(64),
+        }
+    }
+}
+
+impl Channel for Noise {
+    fn read_byte(&self, register: usize) -> u8 {
+        let mask = match register {
+            0 => 0xFF,
+            1 => 0xFF,
+            2 => 0x00,
+            3 => 0x00,
+            4 => 0xBF,
+            _ => unreachable!(),
+        };
+
+        self.registers[register] | mask
+    }
+
+    fn write_byte(&mut self, register: usize, value: u8) {
+        self.registers[register] = value;
+
+        match register {
+            1 => self.length.load(usize::from(value.get_bits(0..6))),
+            2 if !self.dac_enabled() => self.enabled = false,
+            4 if value.get_bit(7) => {
+                self.enabled = self.dac_enabled();
+                self.length.trigger();
+            }
+            _ => {}
+        }
+    }
+
+    fn step_length(&mut self) {
+        if self.length.step(self.registers[4].get_bit(6)) {
+            self.enabled = false;
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.registers[2] & 0xF8 != 0
+    }
+}
+
+impl Snapshot for Noise {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_bool(self.enabled);
+        state.write_usize(self.length.counter);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.registers)?;
+        self.enabled = state.read_bool()?;
+        self.length.counter = state.read_usize()?;
+
+        if self.length.counter > 64 {
+            return Err(StateError::InvalidValue);
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct APU {
+    channel1: Square,
+    channel2: Square,
+    channel3: Wave,
+    channel4: Noise,
+
+    nr50: u8,
+    nr51: u8,
+    enabled: bool,
+
+    frame_sequencer_cycles: usize,
+    frame_sequencer_step: u8,
+    sample_cycles: usize,
+    samples: Vec<f32>,
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            channel1: Square::new(true),
+            channel2: Square::new(false),
+            channel3: Wave::new(),
+            channel4: Noise::new(),
+
+            nr50: 0,
+            nr51: 0,
+            enabled: false,
+
+            frame_sequencer_cycles: 0,
+            frame_sequencer_step: 0,
+            sample_cycles: 0,
+            samples: vec![],
+        }
+    }
+}
+
+impl APU {
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0xFF10..=0xFF14 => self.channel1.read_byte(usize::from(address - 0xFF10)),
+            0xFF15..=0xFF19 => self.channel2.read_byte(usize::from(address - 0xFF15)),
+            0xFF1A..=0xFF1E => self.channel3.read_byte(usize::from(address - 0xFF1A)),
+            0xFF1F..=0xFF23 => self.channel4.read_byte(usize::from(address - 0xFF1F)),
+
+            0xFF24 => self.nr50,
+            0xFF25 => self.nr51,
+            0xFF26 => {
+                let mut nr52 = 0x70;
+                nr52.set_bit(7, self.enabled);
+                nr52.set_bit(0, self.channel1.enabled());
+                nr52.set_bit(1, self.channel2.enabled());
+                nr52.set_bit(2, self.channel3.enabled());
+                nr52.set_bit(3, self.channel4.enabled());
+
+                nr52
+            }
+
+            0xFF30..=0xFF3F => self.channel3.wave_ram[usize::from(address - 0xFF30)],
+
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF26 => {
+                self.set_enabled(value.get_bit(7));
+                return;
+            }
+            // wave RAM can be written regardless of power
+            0xFF30..=0xFF3F => {
+                self.channel3.wave_ram[usize::from(address - 0xFF30)] = value;
+                return;
+            }
+            _ => {}
+        }
+
+        // the registers can't be written whilst the APU is powered off
+        if !self.enabled {
+            return;
+        }
+
+        match address {
+            0xFF10..=0xFF14 => self
+                .channel1
+                .write_byte(usize::from(address - 0xFF10), value),
+            0xFF15..=0xFF19 => self
+                .channel2
+                .write_byte(usize::from(address - 0xFF15), value),
+            0xFF1A..=0xFF1E => self
+                .channel3
+                .write_byte(usize::from(address - 0xFF1A), value),
+            0xFF1F..=0xFF23 => self
+                .channel4
+                .write_byte(usize::from(address - 0xFF1F), value),
+
+            0xFF24 => self.nr50 = value,
+            0xFF25 => self.nr51 = value,
+
+            _ => {}
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if self.enabled && !enabled {
+            // powering off clears every register, but not wave RAM
+            let wave_ram = self.channel3.wave_ram;
+
+            self.channel1 = Square::new(true);
+            self.channel2 = Square::new(false);
+            self.channel3 = Wave::new();
+            self.channel3.wave_ram = wave_ram;
+            self.channel4 = Noise::new();
+
+            self.nr50 = 0;
+            self.nr51 = 0;
+        } else if !self.enabled && enabled {
+            self.frame_sequencer_step = 0;
+        }
+
+        self.enabled = enabled;
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+
+    // moves the oldest whole stereo samples into out, returning how many were moved
+    pub fn take_samples(&mut self, out: &mut [f32]) -> usize {
+        let len = out.len().min(self.samples.len()) / 2 * 2;
+
+        for (sample, value) in out.iter_mut().zip(self.samples.drain(..len)) {
+            *sample = value;
+        }
+
+        len / 2
+    }
+
+    pub fn step(&mut self, cycles: usize) {
+        if self.enabled {
+            self.channel1.step(cycles);
+            self.channel2.step(cycles);
+            self.channel3.step(cycles);
+            self.channel4.step(cycles);
+
+            self.frame_sequencer_cycles += cycles;
+
+            while self.frame_sequencer_cycles >= FRAME_SEQUENCER_CYCLES {
+                self.frame_sequencer_cycles -= FRAME_SEQUENCER_CYCLES;
+                self.step_frame_sequencer();
+            }
+        }
+
+        // resample from the CPU clock to the output rate
+        self.sample_cycles += cycles * SAMPLE_RATE;
+
+        while self.sample_cycles >= CPU_CLOCK {
+            self.sample_cycles -= CPU_CLOCK;
+
+            // once full, new samples are dropped until the frontend takes some, rather than
+            // shifting the whole buffer along for every sample
+            if self.samples.len() < MAX_SAMPLES {
+                let (left, right) = self.mix();
+                self.samples.push(left);
+                self.samples.push(right);
+            }
+        }
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        let channels: [&mut dyn Channel; 4] = [
+            &mut self.channel1,
+            &mut self.channel2,
+            &mut self.channel3,
+            &mut self.channel4,
+        ];
+
+        for channel in channels {
+            match self.frame_sequencer_step {
+                0 | 4 => channel.step_length(),
+                2 | 6 => {
+                    channel.step_length();
+                    channel.step_sweep();
+                }
+                7 => channel.step_envelope(),
+                _ => {}
+            }
+        }
+
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    fn mix(&self) -> (f32, f32) {
+        if !self.enabled {
+            return (0.0, 0.0);
+        }
+
+        let channels: [&dyn Channel; 4] = [
+            &self.channel1,
+            &self.channel2,
+            &self.channel3,
+            &self.channel4,
+        ];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+
+        for (i, channel) in channels.iter().enumerate() {
+            let sample = channel.sample();
+
+            if self.nr51.get_bit(i + 4) {
+                left += sample;
+            }
+
+            if self.nr51.get_bit(i) {
+                right += sample;
+            }
+        }
+
+        // the master volume goes from 1/8 to 8/8
+        let left_volume = f32::from(self.nr50.get_bits(4..7) + 1) / 8.0;
+        let right_volume = f32::from(self.nr50.get_bits(0..3) + 1) / 8.0;
+
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+}
+
+impl Snapshot for APU {
+    fn save(&self, state: &mut StateWriter) {
+        self.channel1.save(state);
+        self.channel2.save(state);
+        self.channel3.save(state);
+        self.channel4.save(state);
+
+        state.write_u8(self.nr50);
+        state.write_u8(self.nr51);
+        state.write_bool(self.enabled);
+
+        state.write_usize(self.frame_sequencer_cycles);
+        state.write_u8(self.frame_sequencer_step);
+        state.write_usize(self.sample_cycles);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.channel1.load(state)?;
+        self.channel2.load(state)?;
+        self.channel3.load(state)?;
+        self.channel4.load(state)?;
+
+        self.nr50 = state.read_u8()?;
+        self.nr51 = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+
+        self.frame_sequencer_cycles = state.read_usize()?;
+        self.frame_sequencer_step = state.read_u8()? % 8;
+        self.sample_cycles = state.read_usize()?;
+
+        self.samples.clear();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_are_capped() {
+        let mut apu = APU::new();
+
+        // two seconds without anything taking the samples
+        for _ in 0..2 * CPU_CLOCK / 1024 {
+            apu.step(1024);
+        }
+
+        assert_eq!(apu.samples().len(), MAX_SAMPLES);
+    }
+
+    #[test]
+    fn taking_samples_makes_room() {
+        let mut apu = APU::new();
+
+        for _ in 0..2 * CPU_CLOCK / 1024 {
+            apu.step(1024);
+        }
+
+        let mut out = [0.0; 64];
+        assert_eq!(apu.take_samples(&mut out), 32);
+
+        for _ in 0..64 {
+            apu.step(1024);
+        }
+
+        assert_eq!(apu.samples().len(), MAX_SAMPLES);
+    }
+}
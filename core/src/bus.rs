@@ -1,142 +1,556 @@
+use crate::apu::APU;
 use crate::cartridge::Cartridge;
+use crate::cheats::Cheats;
+use crate::dma::DMA;
 use crate::interrupts::Interrupts;
+use crate::joypad::Joypad;
+use crate::rom::BootROM;
 use crate::serial::Serial;
+use crate::speed::Speed;
 use crate::timer::Timer;
 use crate::video::Video;
-use bit_field::BitField;
+use crate::wram::WRAM;
+use alloc::boxed::Box;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// a CPU access to an address nothing responds to, reads see 0xFF
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UnmappedAccess {
+    pub address: u16,
+    pub value: u8,
+    pub access: Access,
+}
+
+pub type UnmappedLogger = Box<dyn FnMut(UnmappedAccess)>;
 
 pub struct AddressBus<'a> {
+    boot_rom: &'a mut Option<BootROM>,
     cartridge: &'a mut Cartridge,
-    wram: &'a mut [u8; 8192],
+    wram: &'a mut WRAM,
+    joypad: &'a mut Joypad,
     serial: &'a mut Serial,
     timer: &'a mut Timer,
+    apu: &'a mut APU,
     video: &'a mut Video,
+    dma: &'a mut DMA,
     interrupts: &'a mut Interrupts,
     hram: &'a mut [u8; 127],
+    speed: &'a mut Speed,
+    cheats: &'a Cheats,
+    unmapped_logger: &'a mut Option<UnmappedLogger>,
+
+    cycles: usize,
 }
 
 impl<'a> AddressBus<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        boot_rom: &'a mut Option<BootROM>,
         cartridge: &'a mut Cartridge,
-        wram: &'a mut [u8; 8192],
+        wram: &'a mut WRAM,
+        joypad: &'a mut Joypad,
         serial: &'a mut Serial,
         timer: &'a mut Timer,
+        apu: &'a mut APU,
         video: &'a mut Video,
+        dma: &'a mut DMA,
         interrupts: &'a mut Interrupts,
         hram: &'a mut [u8; 127],
+        speed: &'a mut Speed,
+        cheats: &'a Cheats,
+        unmapped_logger: &'a mut Option<UnmappedLogger>,
     ) -> Self {
         AddressBus {
+            boot_rom,
             cartridge,
             wram,
+            joypad,
             serial,
             timer,
+            apu,
             video,
+            dma,
             interrupts,
             hram,
+            speed,
+            cheats,
+            unmapped_logger,
+
+            cycles: 0,
         }
     }
 }
 
 impl<'a> AddressBus<'a> {
-    pub fn read_byte(&self, address: u16) -> u8 {
-        match address {
-            0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.read_byte(address),
+    // advances every peripheral, each read or write does this for one machine cycle
+    pub fn step(&mut self, cycles: usize) {
+        // in double speed mode the video and sound keep running at the normal rate
+        let video_cycles = if self.speed.double_speed() {
+            cycles / 2
+        } else {
+            cycles
+        };
+
+        self.step_dma(cycles);
+        self.apu.step(video_cycles);
+
+        let interrupts =
+            self.serial.step(cycles) | self.timer.step(cycles) | self.video.step(video_cycles);
+
+        self.interrupts.request(interrupts);
+
+        self.cycles += cycles;
+    }
+
+    // the number of cycles the peripherals have been advanced by
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    fn step_dma(&mut self, cycles: usize) {
+        let source = self.dma.source_address();
+
+        for offset in self.dma.step(cycles) {
+            let value = self.read(source + offset as u16);
+            self.video.write_oam(0xFE00 + offset as u16, value);
+        }
+    }
+}
+
+impl<'a> AddressBus<'a> {
+    pub fn read_byte(&mut self, address: u16) -> u8 {
+        self.step(4);
+
+        // whilst an OAM DMA is running only HRAM and the I/O registers can be accessed
+        if self.dma.active() && address < 0xFF00 {
+            return 0xFF;
+        }
+
+        match self.read_mapped(address) {
+            Some(value) => value,
+            None => {
+                self.log_unmapped(address, 0xFF, Access::Read);
+                0xFF
+            }
+        }
+    }
+
+    // reads without taking any time or being blocked by DMA
+    pub fn peek_byte(&self, address: u16) -> u8 {
+        self.read(address)
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        self.read_mapped(address).unwrap_or(0xFF)
+    }
+
+    // None for addresses nothing responds to, which read as 0xFF
+    fn read_mapped(&self, address: u16) -> Option<u8> {
+        let value = match address {
+            0x0000..=0x00FF if self.boot_rom_mapped() => {
+                self.boot_rom.as_ref().unwrap().read_byte(address)
+            }
+            0x0000..=0x7FFF => self
+                .cheats
+                .patch_rom(address, self.cartridge.read_byte(address)),
+            0xA000..=0xBFFF => self.cartridge.read_byte(address),
             0x8000..=0x9FFF | 0xFE00..=0xFE9F => self.video.read_byte(address),
-            0xC000..=0xDFFF => self.wram[usize::from(address) - 0xC000],
-            0xE000..=0xFDFF => self.wram[usize::from(address) - 0xE000],
+            0xC000..=0xFDFF => self.wram.read_byte(address),
+            // the prohibited area reads as 0 on DMG, unless OAM is blocked
+            0xFEA0..=0xFEFF if self.video.oam_blocked() => 0xFF,
+            0xFEA0..=0xFEFF => 0x00,
 
+            0xFF00 => self.joypad.read_byte(),
             0xFF01 => self.serial.sb,
             0xFF02 => self.serial.sc,
 
-            0xFF04 => self.timer.div,
+            0xFF04 => self.timer.div(),
             0xFF05 => self.timer.tima,
             0xFF06 => self.timer.tma,
             0xFF07 => self.timer.tac,
 
             0xFF0F => self.interrupts.r#if,
 
-            0xFF40 => self.video.lcdc,
-            0xFF41 => {
-                let mut stat = self.video.stat;
-                stat.set_bit(2, self.video.coincidence_flag());
-                stat.set_bits(0..2, self.video.mode as u8);
-
-                stat
+            // NR20 and NR40 don't exist, nor does anything between NR52 and wave RAM
+            0xFF10..=0xFF14 | 0xFF16..=0xFF1E | 0xFF20..=0xFF26 | 0xFF30..=0xFF3F => {
+                self.apu.read_byte(address)
             }
+
+            0xFF40 => self.video.lcdc,
+            0xFF41 => self.video.read_stat(),
             0xFF42 => self.video.scy,
             0xFF43 => self.video.scx,
             0xFF44 => self.video.ly,
             0xFF45 => self.video.lyc,
-            // 0xFF46 => DMA,
-            0xFF47 => self.video.bgp,
-            0xFF48 => self.video.obp0,
-            0xFF49 => self.video.obp1,
+            0xFF46 => self.dma.source,
+            0xFF47 => self.video.bgp(),
+            0xFF48 => self.video.obp0(),
+            0xFF49 => self.video.obp1(),
             0xFF4A => self.video.wy,
             0xFF4B => self.video.wx,
+            // the CGB registers are unmapped on DMG
+            0xFF4D if self.cgb_mode() => self.speed.read_byte(),
+            0xFF4F | 0xFF68..=0xFF6B if self.cgb_mode() => self.video.read_cgb_register(address),
+            0xFF70 if self.cgb_mode() => self.wram.read_svbk(),
 
             0xFF80..=0xFFFE => self.hram[usize::from(address) - 0xFF80],
             0xFFFF => self.interrupts.ie,
 
-            _ => 0xFF,
-        }
+            _ => return None,
+        };
+
+        Some(value)
     }
 
-    pub fn read_word(&self, address: u16) -> u16 {
+    pub fn read_word(&mut self, address: u16) -> u16 {
         let low = self.read_byte(address);
-        let high = self.read_byte(address + 1);
+        let high = self.read_byte(address.wrapping_add(1));
 
         u16::from_le_bytes([low, high])
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8) {
+        self.step(4);
+
+        if self.dma.active() && address < 0xFF00 {
+            return;
+        }
+
+        if !self.write(address, value) {
+            self.log_unmapped(address, value, Access::Write);
+        }
+    }
+
+    // writes without taking any time or being blocked by DMA
+    pub fn poke_byte(&mut self, address: u16, value: u8) {
+        self.write(address, value);
+    }
+
+    fn log_unmapped(&mut self, address: u16, value: u8, access: Access) {
+        if let Some(logger) = self.unmapped_logger {
+            logger(UnmappedAccess {
+                address,
+                value,
+                access,
+            });
+        }
+    }
+
+    // returns false for addresses nothing responds to
+    fn write(&mut self, address: u16, value: u8) -> bool {
         match address {
             0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.write_byte(address, value),
             0x8000..=0x9FFF | 0xFE00..=0xFE9F => self.video.write_byte(address, value),
-            0xC000..=0xDFFF => self.wram[usize::from(address) - 0xC000] = value,
-            0xE000..=0xFDFF => self.wram[usize::from(address) - 0xE000] = value,
+            0xC000..=0xFDFF => self.wram.write_byte(address, value),
+            0xFEA0..=0xFEFF => {}
 
+            0xFF00 => {
+                let interrupts = self.joypad.write_byte(value);
+                self.interrupts.request(interrupts);
+            }
             0xFF01 => self.serial.sb = value,
-            0xFF02 => self.serial.sc = value,
+            0xFF02 => self.serial.write_control(value),
 
-            0xFF04 => self.timer.div = value,
-            0xFF05 => self.timer.tima = value,
+            0xFF04 => self.timer.reset_div(),
+            0xFF05 => self.timer.write_tima(value),
             0xFF06 => self.timer.tma = value,
-            0xFF07 => self.timer.tac = value,
+            0xFF07 => self.timer.write_tac(value),
 
-            0xFF40 => self.video.lcdc = value,
-            0xFF41 => {
-                self.video.stat.set_bits(2..8, value.get_bits(2..8));
+            0xFF10..=0xFF14 | 0xFF16..=0xFF1E | 0xFF20..=0xFF26 | 0xFF30..=0xFF3F => {
+                self.apu.write_byte(address, value)
             }
+
+            0xFF40 => self.video.set_lcdc(value),
+            0xFF41 => self.video.write_stat(value),
             0xFF42 => self.video.scy = value,
             0xFF43 => self.video.scx = value,
-            // 0xFF44 => LY,
-            0xFF45 => self.video.lyc = value,
-            0xFF46 => {
-                let src = u16::from_le_bytes([0, value]);
-
-                for offset in 0..160 {
-                    let value = self.read_byte(src + offset);
-                    self.write_byte(0xFE00 + offset, value);
-                }
-            }
-            0xFF47 => self.video.bgp = value,
-            0xFF48 => self.video.obp0 = value,
-            0xFF49 => self.video.obp1 = value,
+            // LY is read only, writes are ignored
+            0xFF44 => {}
+            0xFF45 => self.video.set_lyc(value),
+            0xFF46 => self.dma.start(value),
+            0xFF47 => self.video.set_bgp(value),
+            0xFF48 => self.video.set_obp0(value),
+            0xFF49 => self.video.set_obp1(value),
             0xFF4A => self.video.wy = value,
             0xFF4B => self.video.wx = value,
+            0xFF4D if self.cgb_mode() => self.speed.write_byte(value),
+            0xFF4F | 0xFF68..=0xFF6B if self.cgb_mode() => {
+                self.video.write_cgb_register(address, value)
+            }
+            0xFF70 if self.cgb_mode() => self.wram.write_svbk(value),
             0xFF0F => self.interrupts.r#if = value,
 
+            0xFF50 => {
+                // any write unmaps the boot ROM until the next reset
+                if let Some(boot_rom) = self.boot_rom {
+                    boot_rom.mapped = false;
+                }
+            }
+
             0xFF80..=0xFFFE => self.hram[usize::from(address) - 0xFF80] = value,
             0xFFFF => self.interrupts.ie = value,
 
-            _ => {}
+            _ => return false,
         };
+
+        true
+    }
+
+    // performs an armed speed switch, returning true if the speed changed
+    pub fn switch_speed(&mut self) -> bool {
+        self.speed.switch()
+    }
+
+    // 16-bit increments and decrements put the old value on the address bus
+    #[cfg(feature = "oam_bug")]
+    pub fn idu_access(&mut self, address: u16) {
+        self.video.corrupt_oam(address);
+    }
+
+    #[cfg(not(feature = "oam_bug"))]
+    pub fn idu_access(&mut self, _address: u16) {}
+
+    fn cgb_mode(&self) -> bool {
+        self.video.cgb_mode()
+    }
+
+    fn boot_rom_mapped(&self) -> bool {
+        match &*self.boot_rom {
+            Some(boot_rom) => boot_rom.mapped,
+            None => false,
+        }
     }
 
     pub fn write_word(&mut self, address: u16, value: u16) {
         let bytes = value.to_le_bytes();
 
         self.write_byte(address, bytes[0]);
-        self.write_byte(address + 1, bytes[1]);
+        self.write_byte(address.wrapping_add(1), bytes[1]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::console;
+    use crate::Console;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn oam_dma_takes_160_machine_cycles() {
+        let mut console = console(&[0x18, 0xFE], false);
+        console.write(0xFF40, 0x00);
+
+        for i in 0..160 {
+            console.write(0xC000 + i, i as u8 + 1);
+        }
+        console.write(0xFF80, 0x42);
+
+        let mut bus = console.bus().unwrap();
+        bus.write_byte(0xFF46, 0xC0);
+        bus.step(320);
+
+        // only HRAM and the I/O registers can be reached mid transfer
+        assert_eq!(bus.read_byte(0xC000), 0xFF);
+        assert_eq!(bus.read_byte(0xFF80), 0x42);
+
+        // 328 cycles, so 82 bytes have been copied
+        assert_eq!(bus.peek_byte(0xFE00 + 81), 82);
+        assert_eq!(bus.peek_byte(0xFE00 + 82), 0);
+
+        bus.step(640 - 328 - 4);
+        assert_eq!(bus.peek_byte(0xFE9F), 0);
+
+        bus.step(4);
+        assert_eq!(bus.peek_byte(0xFE9F), 160);
+        assert_eq!(bus.read_byte(0xC000), 1);
+    }
+
+    // the value of DIV read by an LD A,(a16) that starts the given number of NOPs after DIV is
+    // reset, DIV increments 64 machine cycles after the reset
+    fn div_after_nops(nops: usize) -> u8 {
+        let mut program = vec![0xE0, 0x04]; // LDH (DIV),A
+        program.extend(vec![0x00; nops]); // NOP
+        program.extend_from_slice(&[0xFA, 0x04, 0xFF, 0x18, 0xFE]); // LD A,(DIV); JR -2
+
+        let mut console = console(&program, false);
+
+        for _ in 0..nops + 2 {
+            console.step();
+        }
+
+        console.cpu_state().registers.a
+    }
+
+    #[test]
+    fn reads_see_earlier_machine_cycles() {
+        // the read is the fourth machine cycle of the instruction, so with 60 NOPs DIV ticks
+        // over between the opcode fetch and the read
+        assert_eq!(div_after_nops(59), 0);
+        assert_eq!(div_after_nops(60), 1);
+    }
+
+    // the unmapped accesses made by LDH A,(03); LDH (15),A; LDH A,(4D)
+    fn unmapped_accesses(cgb: bool) -> Vec<UnmappedAccess> {
+        let program = [
+            0xF0, 0x03, // LDH A,(03)
+            0xE0, 0x15, // LDH (NR20),A
+            0xF0, 0x4D, // LDH A,(KEY1)
+            0x18, 0xFE, // JR -2
+        ];
+
+        let accesses = Rc::new(RefCell::new(vec![]));
+        let mut console = console(&program, cgb);
+
+        let logged = Rc::clone(&accesses);
+        console
+            .set_unmapped_access_logger(Box::new(move |access| logged.borrow_mut().push(access)));
+
+        for _ in 0..3 {
+            console.step();
+        }
+
+        let accesses = accesses.borrow().clone();
+        accesses
+    }
+
+    #[test]
+    fn unmapped_accesses_are_logged() {
+        let read = |address| UnmappedAccess {
+            address,
+            value: 0xFF,
+            access: Access::Read,
+        };
+        let write = UnmappedAccess {
+            address: 0xFF15,
+            value: 0xFF,
+            access: Access::Write,
+        };
+
+        assert_eq!(
+            unmapped_accesses(false),
+            [read(0xFF03), write, read(0xFF4D)]
+        );
+
+        // KEY1 only exists on CGB
+        assert_eq!(unmapped_accesses(true), [read(0xFF03), write]);
+    }
+
+    #[test]
+    fn div_write_resets() {
+        let mut console = console(&[0x18, 0xFE], false); // JR -2
+
+        while console.peek(0xFF04) == 0 {
+            console.step();
+        }
+
+        console.write(0xFF04, 0xAB);
+        assert_eq!(console.peek(0xFF04), 0);
+        assert_eq!(console.timer_state().counter, 0);
+    }
+
+    #[test]
+    fn prohibited_area() {
+        let mut console = console(&[0x18, 0xFE], false); // JR -2
+        console.write(0xFF40, 0x00);
+
+        console.write(0xFEA0, 0x12);
+        console.write(0xFEFF, 0x34);
+        assert_eq!(console.peek(0xFEA0), 0x00);
+        assert_eq!(console.peek(0xFEFF), 0x00);
+
+        // the LCD starts again in OAM search, which blocks the area
+        console.write(0xFF40, 0x91);
+        assert_eq!(console.peek(0xFEA0), 0xFF);
+    }
+
+    #[test]
+    fn wave_ram_round_trip() {
+        let mut console = console(&[0x18, 0xFE], false); // JR -2
+
+        for (address, value) in (0xFF30..=0xFF3F).zip((0..16).map(|i| i * 0x11)) {
+            console.write(address, value);
+        }
+
+        for (address, value) in (0xFF30..=0xFF3F).zip((0..16).map(|i| i * 0x11)) {
+            assert_eq!(console.peek(address), value);
+        }
+    }
+
+    #[test]
+    fn colour_palette_ports() {
+        let mut console = console(&[0x18, 0xFE], true); // JR -2
+        console.write(0xFF40, 0x00);
+
+        // background palette 1 colour 2, auto incrementing
+        console.write(0xFF68, 0x80 | 0x0C);
+        console.write(0xFF69, 0x1F);
+        console.write(0xFF69, 0x7C);
+        assert_eq!(console.peek(0xFF68), 0xC0 | 0x0E);
+
+        console.write(0xFF68, 0x0C);
+        assert_eq!(console.peek(0xFF69), 0x1F);
+        console.write(0xFF68, 0x0D);
+        assert_eq!(console.peek(0xFF69), 0x7C);
+
+        // the index wraps from the last byte back to the first
+        console.write(0xFF6A, 0x80 | 0x3F);
+        console.write(0xFF6B, 0x12);
+        assert_eq!(console.peek(0xFF6A), 0xC0);
+        console.write(0xFF6A, 0x3F);
+        assert_eq!(console.peek(0xFF6B), 0x12);
+
+        // each VRAM bank holds its own bytes
+        console.write(0xFF4F, 0x01);
+        console.write(0x8000, 0x42);
+        assert_eq!(console.peek(0xFF4F), 0xFF);
+        console.write(0xFF4F, 0x00);
+        assert_eq!(console.peek(0x8000), 0x00);
+        console.write(0xFF4F, 0x01);
+        assert_eq!(console.peek(0x8000), 0x42);
+    }
+
+    #[test]
+    fn word_accesses_wrap() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0000] = 0xAB;
+        rom[0x100..0x104].copy_from_slice(&[0x31, 0xFF, 0xFF, 0xC1]); // LD SP,0xFFFF; POP BC
+
+        let mut console = Console::new();
+        console.load_rom_bytes(&rom).unwrap();
+
+        let mut bus = console.bus().unwrap();
+        bus.write_word(0xFFFF, 0x1234);
+        assert_eq!(bus.peek_byte(0xFFFF), 0x34);
+        assert_eq!(bus.read_word(0xFFFF), 0xAB34);
+
+        // popping from the top of memory takes its high byte from 0x0000
+        console.step();
+        console.step();
+        let registers = console.cpu_state().registers;
+        assert_eq!(
+            (registers.b, registers.c, registers.sp),
+            (0xAB, 0x34, 0x0001)
+        );
+    }
+
+    #[test]
+    fn ly_writes_ignored() {
+        let mut console = console(&[], false); // NOPs all the way
+
+        while console.peek(0xFF44) != 5 {
+            console.step();
+        }
+
+        console.write(0xFF44, 0x00);
+        assert_eq!(console.peek(0xFF44), 5);
+        console.write(0xFF44, 0x99);
+        assert_eq!(console.peek(0xFF44), 5);
     }
 }
@@ -1,4 +1,27 @@
 use super::rom::{CartridgeType, ROM};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{convert::TryFrom, fmt};
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum CartridgeError {
+    UnsupportedType(u8),
+    InvalidRAMSize(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::UnsupportedType(value) => {
+                write!(f, "unsupported cartridge type {:#04X}", value)
+            }
+            CartridgeError::InvalidRAMSize(value) => write!(f, "invalid RAM size {:#04X}", value),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CartridgeError {}
 
 trait MemoryBankController {
     fn read_byte(&self, rom: &ROM, address: u16) -> u8 {
@@ -6,16 +29,49 @@ trait MemoryBankController {
 
         match address {
             0x0000..=0x7FFF => rom[address],
+            // there's no RAM to read
+            0xA000..=0xBFFF => 0xFF,
             _ => unreachable!(),
         }
     }
 
     fn write_byte(&mut self, _address: u16, _value: u8) {}
+
+    // returns the banking registers to their power-on state, RAM is kept
+    fn reset(&mut self) {}
+
+    fn ram(&self) -> &[u8] {
+        &[]
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    fn save(&self, _state: &mut StateWriter) {}
+
+    fn load(&mut self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+// what the cartridge contains, for frontends deciding whether to persist saves
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CartridgeInfo {
+    pub mbc: CartridgeType,
+    pub has_ram: bool,
+    pub has_battery: bool,
+    pub has_rtc: bool,
+    // 16KB banks, as given in the header
+    pub rom_banks: usize,
+    // 8KB banks, MBC2's 512 nibbles count as one
+    pub ram_banks: usize,
 }
 
 pub struct Cartridge {
     rom: ROM,
     mbc: Box<MemoryBankController>,
+    mbc_type: CartridgeType,
 }
 
 impl Cartridge {
@@ -26,19 +82,111 @@ impl Cartridge {
     pub fn write_byte(&mut self, address: u16, value: u8) {
         self.mbc.write_byte(address, value);
     }
+
+    pub fn reset(&mut self) {
+        self.mbc.reset();
+    }
+
+    pub fn info(&self) -> CartridgeInfo {
+        let ram_size = self.mbc.ram().len();
+
+        CartridgeInfo {
+            mbc: self.mbc_type,
+            has_ram: ram_size > 0,
+            has_battery: self.rom.has_battery(),
+            has_rtc: self.rom.has_rtc(),
+            rom_banks: self.rom.rom_size().map_or(0, |size| size / 0x4000),
+            ram_banks: ram_size.div_ceil(0x2000),
+        }
+    }
+
+    // bit 7 of the CGB flag is set by games which use the CGB features
+    pub fn supports_cgb(&self) -> bool {
+        self.rom.cgb_flag().get_bit(7)
+    }
+
+    pub fn load_ram(&mut self, ram: &[u8]) {
+        if !self.rom.has_battery() {
+            return;
+        }
+
+        let dest = self.mbc.ram_mut();
+        let len = dest.len().min(ram.len());
+
+        dest[..len].copy_from_slice(&ram[..len]);
+    }
+
+    pub fn dump_ram(&self) -> Vec<u8> {
+        if !self.rom.has_battery() {
+            return vec![];
+        }
+
+        self.mbc.ram().to_vec()
+    }
 }
 
-impl From<ROM> for Cartridge {
-    fn from(rom: ROM) -> Self {
-        let mbc: Box<MemoryBankController> = match rom.cartridge_type() {
+impl Snapshot for Cartridge {
+    fn save(&self, state: &mut StateWriter) {
+        // identify the cartridge by its type and global checksum
+        state.write_u8(self.rom[0x147]);
+        state.write_u8(self.rom[0x14E]);
+        state.write_u8(self.rom[0x14F]);
+
+        self.mbc.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for &address in [0x147, 0x14E, 0x14F].iter() {
+            if state.read_u8()? != self.rom[address] {
+                return Err(StateError::CartridgeMismatch);
+            }
+        }
+
+        self.mbc.load(state)
+    }
+}
+
+impl TryFrom<ROM> for Cartridge {
+    type Error = CartridgeError;
+
+    fn try_from(rom: ROM) -> Result<Self, Self::Error> {
+        let cartridge_type = rom
+            .cartridge_type()
+            .ok_or(CartridgeError::UnsupportedType(rom[0x147]))?;
+
+        let ram_size = ram_size(&rom, &cartridge_type)?;
+
+        let mbc: Box<MemoryBankController> = match cartridge_type {
             CartridgeType::ROMOnly => Box::new(MBC0 {}),
-            CartridgeType::MBC1 => Box::new(MBC1::new(rom.ram_size())),
+            CartridgeType::MBC1 => Box::new(MBC1::new(ram_size)),
+            CartridgeType::MBC2 => Box::new(MBC2::new(ram_size)),
         };
 
-        Cartridge { rom, mbc }
+        Ok(Cartridge {
+            rom,
+            mbc,
+            mbc_type: cartridge_type,
+        })
     }
 }
 
+// the header doesn't always describe the RAM the MBC actually has
+fn ram_size(rom: &ROM, cartridge_type: &CartridgeType) -> Result<usize, CartridgeError> {
+    let header_size = rom
+        .ram_size()
+        .ok_or(CartridgeError::InvalidRAMSize(rom[0x149]))?;
+
+    let ram_size = match cartridge_type {
+        // 512 half bytes are built into the MBC2 itself
+        CartridgeType::MBC2 => 512,
+        // some battery backed carts report no RAM, assume a single bank
+        CartridgeType::MBC1 if header_size == 0 && rom.has_battery() => 0x2000,
+        _ => header_size,
+    };
+
+    Ok(ram_size)
+}
+
 pub struct MBC0;
 
 impl MemoryBankController for MBC0 {}
@@ -70,6 +218,27 @@ impl MBC1 {
     }
 }
 
+impl MBC1 {
+    // in ROM mode the 2-bit bank register supplies the upper bits of the ROM bank,
+    // which is why banks 0x20, 0x40 and 0x60 end up as 0x21, 0x41 and 0x61
+    fn effective_rom_bank(&self) -> usize {
+        match self.bank_mode {
+            BankMode::ROM => usize::from(self.ram_bank << 5 | self.rom_bank),
+            BankMode::RAM => usize::from(self.rom_bank),
+        }
+    }
+
+    // banks past the end of the RAM wrap around
+    fn effective_ram_bank(&self) -> usize {
+        let banks = (self.ram.len() / 0x2000).max(1);
+
+        match self.bank_mode {
+            BankMode::ROM => 0,
+            BankMode::RAM => usize::from(self.ram_bank) % banks,
+        }
+    }
+}
+
 impl MemoryBankController for MBC1 {
     fn read_byte(&self, rom: &ROM, address: u16) -> u8 {
         let address = usize::from(address);
@@ -79,7 +248,8 @@ impl MemoryBankController for MBC1 {
             0x0000..=0x3FFF => rom[address],
             // ROM Bank 01-7F (Read Only)
             0x4000..=0x7FFF => {
-                let offset = self.rom_bank as usize * 0x4000;
+                // banks past the end of the ROM wrap around, like the unconnected address lines
+                let offset = (self.effective_rom_bank() % rom.banks()) * 0x4000;
                 rom[offset + address - 0x4000]
             }
             // RAM Bank 00-03, if any (Read/Write)
@@ -88,13 +258,24 @@ impl MemoryBankController for MBC1 {
                     return 0xFF;
                 }
 
-                let offset = self.ram_bank as usize * 0x2000;
-                self.ram[offset + address - 0xA000]
+                // a cartridge may have less than a whole bank of RAM, or none at all
+                let offset = self.effective_ram_bank() * 0x2000;
+                self.ram
+                    .get(offset + address - 0xA000)
+                    .copied()
+                    .unwrap_or(0xFF)
             }
             _ => unreachable!(),
         }
     }
 
+    fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.rom_bank = 1;
+        self.ram_bank = 0;
+        self.bank_mode = BankMode::ROM;
+    }
+
     fn write_byte(&mut self, address: u16, value: u8) {
         let address = usize::from(address);
 
@@ -105,26 +286,22 @@ impl MemoryBankController for MBC1 {
             }
             // ROM Bank Number (Write Only)
             0x2000..=0x3FFF => {
-                self.rom_bank.set_bits(0..5, value);
+                // bank 0 can't be selected here, it is translated to bank 1
+                self.rom_bank = match value.get_bits(0..5) {
+                    0x00 => 0x01,
+                    bank => bank,
+                };
             }
             // RAM Bank Number - or - Upper Bits of ROM Bank Number (Write Only)
             0x4000..=0x5FFF => {
-                match self.bank_mode {
-                    BankMode::ROM => {
-                        self.rom_bank.set_bits(5..6, value);
-                    }
-                    BankMode::RAM => match value {
-                        0x00..=0x03 => self.ram_bank = value,
-                        _ => unreachable!(),
-                    },
-                };
+                self.ram_bank = value.get_bits(0..2);
             }
             // ROM/RAM Mode Select (Write Only)
             0x6000..=0x7FFF => {
-                self.bank_mode = match value {
-                    0x00 => BankMode::ROM,
-                    0x01 => BankMode::RAM,
-                    _ => unreachable!(),
+                self.bank_mode = if value.get_bit(0) {
+                    BankMode::RAM
+                } else {
+                    BankMode::ROM
                 };
             }
             // RAM Bank 00-03, if any (Read/Write)
@@ -133,10 +310,334 @@ impl MemoryBankController for MBC1 {
                     return;
                 }
 
-                let offset = self.ram_bank as usize * 0x2000;
-                self.ram[offset + address - 0xA000] = value;
+                let offset = self.effective_ram_bank() * 0x2000;
+                if let Some(byte) = self.ram.get_mut(offset + address - 0xA000) {
+                    *byte = value;
+                }
             }
             _ => unreachable!(),
         }
     }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_u8(match self.bank_mode {
+            BankMode::ROM => 0,
+            BankMode::RAM => 1,
+        });
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.ram)?;
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank = state.read_u8()?;
+        self.ram_bank = state.read_u8()?;
+        self.bank_mode = match state.read_u8()? {
+            0 => BankMode::ROM,
+            1 => BankMode::RAM,
+            _ => return Err(StateError::InvalidValue),
+        };
+
+        Ok(())
+    }
+}
+
+pub struct MBC2 {
+    // only the lower 4 bits of each byte are used
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl MBC2 {
+    fn new(ram_size: usize) -> Self {
+        MBC2 {
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl MemoryBankController for MBC2 {
+    fn read_byte(&self, rom: &ROM, address: u16) -> u8 {
+        let address = usize::from(address);
+
+        match address {
+            // ROM Bank 00 (Read Only)
+            0x0000..=0x3FFF => rom[address],
+            // ROM Bank 01-0F (Read Only)
+            0x4000..=0x7FFF => {
+                let offset = (usize::from(self.rom_bank) % rom.banks()) * 0x4000;
+                rom[offset + address - 0x4000]
+            }
+            // 512x4 bits RAM, repeated throughout the area, the upper bits read as 1
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return 0xFF;
+                }
+
+                self.ram[address % self.ram.len()] | 0xF0
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.rom_bank = 1;
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            // RAM Enable or ROM Bank Number, depending on bit 8 of the address (Write Only)
+            0x0000..=0x3FFF => {
+                if address.get_bit(8) {
+                    self.rom_bank = match value.get_bits(0..4) {
+                        0x00 => 0x01,
+                        bank => bank,
+                    };
+                } else {
+                    self.ram_enabled = value.get_bits(0..4) == 0x0A;
+                }
+            }
+            0x4000..=0x7FFF => {}
+            // 512x4 bits RAM (Read/Write)
+            0xA000..=0xBFFF => {
+                if !self.ram_enabled {
+                    return;
+                }
+
+                let len = self.ram.len();
+                self.ram[usize::from(address) % len] = value.get_bits(0..4);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_bool(self.ram_enabled);
+        state.write_u8(self.rom_bank);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.ram)?;
+        self.ram_enabled = state.read_bool()?;
+        self.rom_bank = state.read_u8()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a cartridge whose 16KB banks each start with their own bank number
+    fn numbered_banks(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Cartridge {
+        let banks = 2 << rom_size;
+        let mut bytes = vec![0; banks * 0x4000];
+
+        for bank in 0..banks {
+            bytes[bank * 0x4000] = bank as u8;
+        }
+
+        bytes[0x147] = cartridge_type;
+        bytes[0x148] = rom_size;
+        bytes[0x149] = ram_size;
+
+        Cartridge::try_from(ROM::from_bytes(bytes)).unwrap()
+    }
+
+    #[test]
+    fn mbc1_bank_zero_selects_bank_one() {
+        let mut cartridge = numbered_banks(0x01, 0x05, 0x00);
+
+        cartridge.write_byte(0x2000, 0x00);
+        assert_eq!(cartridge.read_byte(0x4000), 0x01);
+
+        // only the low 5 bits are written, so 0x20 is also bank 0
+        cartridge.write_byte(0x2000, 0x20);
+        assert_eq!(cartridge.read_byte(0x4000), 0x01);
+
+        // with the upper bits set bank 0x20 is unreachable and gives 0x21
+        cartridge.write_byte(0x4000, 0x01);
+        assert_eq!(cartridge.read_byte(0x4000), 0x21);
+
+        cartridge.write_byte(0x2000, 0x05);
+        assert_eq!(cartridge.read_byte(0x4000), 0x25);
+    }
+
+    #[test]
+    fn info_with_unknown_rom_size() {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x148] = 0x50;
+
+        let cartridge = Cartridge::try_from(ROM::from_bytes(bytes)).unwrap();
+        assert_eq!(cartridge.info().rom_banks, 0);
+    }
+
+    #[test]
+    fn info_rom_banks() {
+        let mut bytes = vec![0; 0x10000];
+        bytes[0x147] = 0x01;
+        bytes[0x148] = 0x01;
+
+        let cartridge = Cartridge::try_from(ROM::from_bytes(bytes)).unwrap();
+        assert_eq!(cartridge.info().rom_banks, 4);
+    }
+
+    #[test]
+    fn info_mbc1_ram_battery() {
+        let mut bytes = vec![0; 0x10000];
+        bytes[0x147] = 0x03; // MBC1+RAM+BATTERY
+        bytes[0x148] = 0x01;
+        bytes[0x149] = 0x03; // 32KB
+
+        let info = Cartridge::try_from(ROM::from_bytes(bytes)).unwrap().info();
+        assert_eq!(info.mbc, CartridgeType::MBC1);
+        assert!(info.has_ram);
+        assert!(info.has_battery);
+        assert!(!info.has_rtc);
+        assert_eq!(info.ram_banks, 4);
+    }
+
+    #[test]
+    fn battery_ram_round_trip() {
+        let mut cartridge = numbered_banks(0x03, 0x01, 0x02);
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_byte(0xA000, 0x42);
+        cartridge.write_byte(0xBFFF, 0x24);
+
+        let ram = cartridge.dump_ram();
+        assert_eq!(ram.len(), 0x2000);
+
+        let mut restored = numbered_banks(0x03, 0x01, 0x02);
+        restored.load_ram(&ram);
+        restored.write_byte(0x0000, 0x0A);
+        assert_eq!(restored.read_byte(0xA000), 0x42);
+        assert_eq!(restored.read_byte(0xBFFF), 0x24);
+    }
+
+    #[test]
+    fn ram_without_battery_isnt_saved() {
+        let mut cartridge = numbered_banks(0x02, 0x01, 0x02);
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_byte(0xA000, 0x42);
+
+        assert_eq!(cartridge.dump_ram(), []);
+    }
+
+    #[test]
+    fn out_of_range_banks_wrap() {
+        // the header claims 1MB but the file only holds 4 banks
+        let mut bytes = vec![0; 4 * 0x4000];
+        for bank in 0..4 {
+            bytes[bank * 0x4000] = bank as u8;
+        }
+        bytes[0x147] = 0x01;
+        bytes[0x148] = 0x05;
+
+        let mut cartridge = Cartridge::try_from(ROM::from_bytes(bytes)).unwrap();
+
+        cartridge.write_byte(0x2000, 0x06);
+        assert_eq!(cartridge.read_byte(0x4000), 2);
+
+        cartridge.write_byte(0x2000, 0x1F);
+        assert_eq!(cartridge.read_byte(0x4000), 3);
+    }
+
+    #[test]
+    fn reads_past_truncated_rom() {
+        let mut bytes = vec![0x11; 0x5000];
+        bytes[0x147] = 0x01;
+        bytes[0x148] = 0x01;
+        bytes[0x149] = 0x00;
+
+        let cartridge = Cartridge::try_from(ROM::from_bytes(bytes)).unwrap();
+        assert_eq!(cartridge.read_byte(0x4FFF), 0x11);
+        assert_eq!(cartridge.read_byte(0x5000), 0xFF);
+        assert_eq!(cartridge.read_byte(0x7FFF), 0xFF);
+    }
+
+    #[test]
+    fn unsupported_headers_are_errors() {
+        let header = |cartridge_type, ram_size| {
+            let mut bytes = vec![0; 0x8000];
+            bytes[0x147] = cartridge_type;
+            bytes[0x149] = ram_size;
+            Cartridge::try_from(ROM::from_bytes(bytes)).err()
+        };
+
+        // MBC3+RAM+BATTERY
+        assert_eq!(
+            header(0x13, 0x03),
+            Some(CartridgeError::UnsupportedType(0x13))
+        );
+        assert_eq!(
+            header(0x01, 0x06),
+            Some(CartridgeError::InvalidRAMSize(0x06))
+        );
+        assert_eq!(header(0x01, 0x03), None);
+    }
+
+    #[test]
+    fn ram_sized_by_mbc() {
+        // MBC2+BATTERY, whose header describes no RAM
+        let mut mbc2 = numbered_banks(0x06, 0x01, 0x00);
+        assert_eq!(mbc2.dump_ram().len(), 512);
+
+        // it repeats every 512 bytes, holding only the low half of each byte
+        mbc2.write_byte(0x0000, 0x0A);
+        mbc2.write_byte(0xA000, 0x3C);
+        assert_eq!(mbc2.read_byte(0xA000), 0xFC);
+        assert_eq!(mbc2.read_byte(0xA200), 0xFC);
+        assert_eq!(mbc2.read_byte(0xBE00), 0xFC);
+
+        // MBC1+RAM+BATTERY claiming no RAM still gets a bank
+        assert_eq!(numbered_banks(0x03, 0x01, 0x00).dump_ram().len(), 0x2000);
+    }
+
+    #[test]
+    fn mbc1_ram_bank_past_end() {
+        // MBC1+RAM+BATTERY with a single 8KB bank
+        let mut cartridge = numbered_banks(0x03, 0x01, 0x02);
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_byte(0xA000, 0x42);
+
+        // RAM banking mode, bank 3 wraps around to the only bank there is
+        cartridge.write_byte(0x6000, 0x01);
+        cartridge.write_byte(0x4000, 0x03);
+        assert_eq!(cartridge.read_byte(0xA000), 0x42);
+        cartridge.write_byte(0xA000, 0x24);
+
+        cartridge.write_byte(0x4000, 0x00);
+        assert_eq!(cartridge.read_byte(0xA000), 0x24);
+
+        // 2KB of RAM doesn't fill the bank, the rest reads as 0xFF
+        let mut cartridge = numbered_banks(0x03, 0x01, 0x01);
+        cartridge.write_byte(0x0000, 0x0A);
+        cartridge.write_byte(0xA800, 0x42);
+        assert_eq!(cartridge.read_byte(0xA800), 0xFF);
+    }
 }
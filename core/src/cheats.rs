@@ -0,0 +1,178 @@
+use alloc::{vec, vec::Vec};
+use core::fmt;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum CheatError {
+    InvalidLength,
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::InvalidLength => write!(f, "cheat code is the wrong length"),
+            CheatError::InvalidCharacter(c) => {
+                write!(f, "cheat code contains an invalid character {:?}", c)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CheatError {}
+
+// replaces a byte read from the cartridge ROM
+struct GenieCode {
+    address: u16,
+    value: u8,
+    compare: Option<u8>,
+}
+
+// writes a byte into RAM every frame
+struct GameSharkCode {
+    address: u16,
+    value: u8,
+}
+
+pub struct Cheats {
+    genie_codes: Vec<GenieCode>,
+    gameshark_codes: Vec<GameSharkCode>,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats {
+            genie_codes: vec![],
+            gameshark_codes: vec![],
+        }
+    }
+}
+
+impl Cheats {
+    // ABC-DEF or ABC-DEF-GHI, the dashes are optional
+    pub fn add_genie_code(&mut self, code: &str) -> Result<(), CheatError> {
+        let digits = hex_digits(code)?;
+
+        if digits.len() != 6 && digits.len() != 9 {
+            return Err(CheatError::InvalidLength);
+        }
+
+        let value = (digits[0] << 4) | digits[1];
+        let address = (u16::from(digits[5] ^ 0xF) << 12)
+            | (u16::from(digits[2]) << 8)
+            | (u16::from(digits[3]) << 4)
+            | u16::from(digits[4]);
+
+        // H is only a checksum, the compare value is scrambled across G and I
+        let compare = if digits.len() == 9 {
+            Some(((digits[6] << 4) | digits[8]).rotate_right(2) ^ 0xBA)
+        } else {
+            None
+        };
+
+        self.genie_codes.push(GenieCode {
+            address,
+            value,
+            compare,
+        });
+
+        Ok(())
+    }
+
+    // TTVVLLHH, where TT is the RAM bank type, VV the value and HHLL the address
+    pub fn add_gameshark_code(&mut self, code: &str) -> Result<(), CheatError> {
+        let digits = hex_digits(code)?;
+
+        if digits.len() != 8 {
+            return Err(CheatError::InvalidLength);
+        }
+
+        let bytes: Vec<u8> = digits.chunks(2).map(|d| (d[0] << 4) | d[1]).collect();
+
+        self.gameshark_codes.push(GameSharkCode {
+            address: u16::from_le_bytes([bytes[2], bytes[3]]),
+            value: bytes[1],
+        });
+
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.genie_codes.clear();
+        self.gameshark_codes.clear();
+    }
+
+    // applies any Game Genie codes to a byte read from the cartridge ROM
+    pub fn patch_rom(&self, address: u16, value: u8) -> u8 {
+        self.genie_codes
+            .iter()
+            .find(|code| code.address == address && code.compare.is_none_or(|c| c == value))
+            .map_or(value, |code| code.value)
+    }
+
+    // the address and value of every GameShark write
+    pub fn ram_writes(&self) -> Vec<(u16, u8)> {
+        self.gameshark_codes
+            .iter()
+            .map(|code| (code.address, code.value))
+            .collect()
+    }
+}
+
+fn hex_digits(code: &str) -> Result<Vec<u8>, CheatError> {
+    code.chars()
+        .filter(|c| *c != '-')
+        .map(|c| match c.to_digit(16) {
+            Some(digit) => Ok(digit as u8),
+            None => Err(CheatError::InvalidCharacter(c)),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::console;
+
+    #[test]
+    fn genie_codes() {
+        let mut cheats = Cheats::new();
+
+        // replaces 0xC8 at 0x4A17 with 0x00
+        cheats.add_genie_code("00A-17B-C49").unwrap();
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0x00);
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC9), 0xC9);
+        assert_eq!(cheats.patch_rom(0x4A18, 0xC8), 0xC8);
+
+        // without a compare value every read is replaced
+        cheats.add_genie_code("3E150F").unwrap();
+        assert_eq!(cheats.patch_rom(0x0150, 0x00), 0x3E);
+        assert_eq!(cheats.patch_rom(0x0150, 0xFF), 0x3E);
+
+        assert_eq!(
+            cheats.add_genie_code("00A-17B-C4"),
+            Err(CheatError::InvalidLength)
+        );
+        assert_eq!(
+            cheats.add_genie_code("00A-17G"),
+            Err(CheatError::InvalidCharacter('G'))
+        );
+    }
+
+    #[test]
+    fn codes_apply_to_the_console() {
+        let mut console = console(&[0x18, 0xFE], false); // JR -2
+
+        console.add_genie_code("12A-17B").unwrap();
+        assert_eq!(console.read(0x4A17), 0x12);
+
+        // writes 0x99 to 0xC134 every frame
+        console.add_gameshark_code("019934C1").unwrap();
+        console.run_frame();
+        assert_eq!(console.read(0xC134), 0x99);
+
+        console.write(0xC134, 0x00);
+        console.run_frame();
+        assert_eq!(console.read(0xC134), 0x99);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use core::ops::Range;
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct DMA {
+    pub source: u8,
+
+    offset: usize,
+    cycles: usize,
+    active: bool,
+}
+
+impl DMA {
+    pub fn new() -> Self {
+        DMA {
+            source: 0,
+
+            offset: 0,
+            cycles: 0,
+            active: false,
+        }
+    }
+}
+
+impl DMA {
+    pub fn start(&mut self, source: u8) {
+        self.source = source;
+
+        self.offset = 0;
+        self.cycles = 0;
+        self.active = true;
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    pub fn source_address(&self) -> u16 {
+        u16::from_le_bytes([0, self.source])
+    }
+
+    // returns the offsets of the bytes to be copied into OAM during these cycles
+    pub fn step(&mut self, cycles: usize) -> Range<usize> {
+        if !self.active {
+            return 0..0;
+        }
+
+        // one byte is copied every machine cycle, 160 bytes in total
+        self.cycles += cycles;
+
+        let start = self.offset;
+        let end = (self.cycles / 4).min(160);
+
+        self.offset = end;
+
+        if end == 160 {
+            self.active = false;
+        }
+
+        start..end
+    }
+}
+
+impl Snapshot for DMA {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.source);
+        state.write_usize(self.offset);
+        state.write_usize(self.cycles);
+        state.write_bool(self.active);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.read_u8()?;
+        self.offset = state.read_usize()?;
+        self.cycles = state.read_usize()?;
+        self.active = state.read_bool()?;
+
+        Ok(())
+    }
+}
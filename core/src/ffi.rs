@@ -1,5 +1,7 @@
-use crate::{cartridge::Cartridge, rom::ROM, Console};
-use std::ffi::CString;
+use crate::{cartridge::Cartridge, rom::ROM, Console, StateError};
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::panic::{self, AssertUnwindSafe};
 
 #[no_mangle]
 pub extern "C" fn gb_create() -> *mut Console {
@@ -9,55 +11,134 @@ pub extern "C" fn gb_create() -> *mut Console {
 
 #[no_mangle]
 pub unsafe extern "C" fn gb_destroy(gb: *mut Console) {
-    assert!(!gb.is_null());
+    if gb.is_null() {
+        return;
+    }
 
     drop(Box::from_raw(gb));
 }
 
+#[repr(C)]
+#[derive(Debug, Eq, PartialEq)]
+pub enum LoadStatus {
+    Ok = 0,
+    NullPointer = 1,
+    IOError = 2,
+    UnsupportedCartridge = 3,
+    Panic = 4,
+}
+
+// title receives the nul terminated ROM title, truncated to fit in title_len bytes
 #[no_mangle]
 pub unsafe extern "C" fn gb_load_rom(
     gb: *mut Console,
     path: *const std::os::raw::c_char,
     title: *mut std::os::raw::c_char,
-) {
-    assert!(!path.is_null());
+    title_len: usize,
+) -> LoadStatus {
+    if gb.is_null() || path.is_null() {
+        return LoadStatus::NullPointer;
+    }
+
+    // unwinding across the extern "C" boundary is undefined behaviour
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        load_rom(&mut *gb, CStr::from_ptr(path), title, title_len)
+    }));
+
+    result.unwrap_or(LoadStatus::Panic)
+}
+
+unsafe fn load_rom(
+    gb: &mut Console,
+    path: &CStr,
+    title: *mut std::os::raw::c_char,
+    title_len: usize,
+) -> LoadStatus {
+    let path = path.to_string_lossy().into_owned();
+
+    let rom = match ROM::from_file(&path) {
+        Ok(rom) => rom,
+        Err(_) => return LoadStatus::IOError,
+    };
 
-    let path = std::ffi::CStr::from_ptr(path)
-        .to_string_lossy()
-        .into_owned();
-    println!("Loading {:?}", path);
+    insert_rom(gb, rom, title, title_len)
+}
 
-    let rom = ROM::from_file(path).unwrap();
-    let rom_title = match CString::new(rom.title()) {
-        Ok(title) => title,
-        _ => panic!(),
+unsafe fn insert_rom(
+    gb: &mut Console,
+    rom: ROM,
+    title: *mut std::os::raw::c_char,
+    title_len: usize,
+) -> LoadStatus {
+    let rom_title = rom.title();
+
+    let cartridge = match Cartridge::try_from(rom) {
+        Ok(cartridge) => cartridge,
+        Err(_) => return LoadStatus::UnsupportedCartridge,
     };
 
-    let buf: &mut [std::os::raw::c_char] = std::slice::from_raw_parts_mut(title, 16);
-    rom_title.into_bytes_with_nul().iter().enumerate().for_each(|(i, c)| {
-        buf[i] = *c as i8;
-    });
+    // the title is only written once the cartridge has been accepted
+    if !title.is_null() && title_len > 0 {
+        let len = rom_title.len().min(title_len - 1);
+
+        let buf: &mut [std::os::raw::c_char] = std::slice::from_raw_parts_mut(title, title_len);
+        for (c, byte) in buf.iter_mut().zip(rom_title.bytes().take(len)) {
+            *c = byte as std::os::raw::c_char;
+        }
+        buf[len] = 0;
+    }
 
-    let cartridge = Cartridge::from(rom);
-    (&mut *gb).insert_cartridge(cartridge);
+    gb.insert_cartridge(cartridge);
+
+    LoadStatus::Ok
 }
 
+// as gb_load_rom, but from a ROM already in memory
 #[no_mangle]
-pub unsafe extern "C" fn gb_run_frame(gb: *mut Console) {
-    assert!(!gb.is_null());
+pub unsafe extern "C" fn gb_load_rom_bytes(
+    gb: *mut Console,
+    bytes: *const std::os::raw::c_uchar,
+    len: usize,
+    title: *mut std::os::raw::c_char,
+    title_len: usize,
+) -> LoadStatus {
+    if gb.is_null() || bytes.is_null() {
+        return LoadStatus::NullPointer;
+    }
 
-    let start = std::time::Instant::now();
+    let bytes: &[std::os::raw::c_uchar] = std::slice::from_raw_parts(bytes, len);
+    let rom = ROM::from_bytes(bytes.to_vec());
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        insert_rom(&mut *gb, rom, title, title_len)
+    }));
+
+    result.unwrap_or(LoadStatus::Panic)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gb_run_frame(gb: *mut Console) {
+    if gb.is_null() {
+        return;
+    }
 
     (&mut *gb).run_frame();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gb_reset(gb: *mut Console) {
+    if gb.is_null() {
+        return;
+    }
 
-    let end = std::time::Instant::now();
-    println!("{:?}", end - start);
+    (&mut *gb).reset();
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn gb_get_frame_buffer(gb: *mut Console, buf: *mut std::os::raw::c_uchar) {
-    assert!(!gb.is_null());
-    assert!(!buf.is_null());
+    if gb.is_null() || buf.is_null() {
+        return;
+    }
 
     let framebuffer = (&mut *gb).video.framebuffer();
     let framebuffer: Vec<_> = framebuffer.iter().map(|x| *x as u8).collect();
@@ -65,3 +146,410 @@ pub unsafe extern "C" fn gb_get_frame_buffer(gb: *mut Console, buf: *mut std::os
     let buf: &mut [std::os::raw::c_uchar] = std::slice::from_raw_parts_mut(buf, 160 * 144);
     buf.copy_from_slice(&framebuffer);
 }
+
+// palette points to four RGBA colours, or is null to use the console's palette
+#[no_mangle]
+pub unsafe extern "C" fn gb_get_frame_buffer_rgba(
+    gb: *mut Console,
+    palette: *const std::os::raw::c_uchar,
+    buf: *mut std::os::raw::c_uchar,
+) {
+    if gb.is_null() || buf.is_null() {
+        return;
+    }
+
+    let framebuffer = if palette.is_null() {
+        (&*gb).framebuffer_rgba()
+    } else {
+        let palette: &[std::os::raw::c_uchar] = std::slice::from_raw_parts(palette, 16);
+
+        let mut colours = [[0; 4]; 4];
+        for (colour, bytes) in colours.iter_mut().zip(palette.chunks(4)) {
+            colour.copy_from_slice(bytes);
+        }
+
+        (&*gb).video.framebuffer_rgba(colours)
+    };
+
+    let buf: &mut [std::os::raw::c_uchar] = std::slice::from_raw_parts_mut(buf, 160 * 144 * 4);
+    buf.copy_from_slice(&framebuffer);
+}
+
+// colours points to four RGBA colours, from White to Black
+#[no_mangle]
+pub unsafe extern "C" fn gb_set_dmg_palette(
+    gb: *mut Console,
+    colours: *const std::os::raw::c_uchar,
+) {
+    if gb.is_null() || colours.is_null() {
+        return;
+    }
+
+    let colours: &[std::os::raw::c_uchar] = std::slice::from_raw_parts(colours, 16);
+
+    let mut palette = [[0; 4]; 4];
+    for (colour, bytes) in palette.iter_mut().zip(colours.chunks(4)) {
+        colour.copy_from_slice(bytes);
+    }
+
+    (&mut *gb).set_dmg_palette(palette);
+}
+
+// buf must hold 128x192 shades, drawn with the current background palette
+#[no_mangle]
+pub unsafe extern "C" fn gb_get_tile_atlas(gb: *mut Console, buf: *mut std::os::raw::c_uchar) {
+    if gb.is_null() || buf.is_null() {
+        return;
+    }
+
+    let video = &(&*gb).video;
+    let atlas = video.tile_atlas(video.background_palette());
+    let atlas: Vec<_> = atlas.iter().map(|x| *x as u8).collect();
+
+    let buf: &mut [std::os::raw::c_uchar] = std::slice::from_raw_parts_mut(buf, 128 * 192);
+    buf.copy_from_slice(&atlas);
+}
+
+// buf must hold 256x256 shades, scx and scy receive the viewport if they aren't null
+#[no_mangle]
+pub unsafe extern "C" fn gb_get_background_map(
+    gb: *mut Console,
+    buf: *mut std::os::raw::c_uchar,
+    scx: *mut std::os::raw::c_uchar,
+    scy: *mut std::os::raw::c_uchar,
+) {
+    if gb.is_null() || buf.is_null() {
+        return;
+    }
+
+    let video = &(&*gb).video;
+    let map = video.render_background_map(video.background_palette());
+    let map: Vec<_> = map.iter().map(|x| *x as u8).collect();
+
+    let buf: &mut [std::os::raw::c_uchar] = std::slice::from_raw_parts_mut(buf, 256 * 256);
+    buf.copy_from_slice(&map);
+
+    let (x, y) = video.viewport();
+
+    if !scx.is_null() {
+        *scx = x;
+    }
+
+    if !scy.is_null() {
+        *scy = y;
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gb_get_ram_size(gb: *mut Console) -> usize {
+    if gb.is_null() {
+        return 0;
+    }
+
+    (&*gb).dump_ram().len()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gb_dump_ram(
+    gb: *mut Console,
+    buf: *mut std::os::raw::c_uchar,
+    len: usize,
+) -> usize {
+    if gb.is_null() || buf.is_null() {
+        return 0;
+    }
+
+    let ram = (&*gb).dump_ram();
+    let len = ram.len().min(len);
+
+    let buf: &mut [std::os::raw::c_uchar] = std::slice::from_raw_parts_mut(buf, len);
+    buf.copy_from_slice(&ram[..len]);
+
+    len
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn gb_load_ram(
+    gb: *mut Console,
+    buf: *const std::os::raw::c_uchar,
+    len: usize,
+) {
+    if gb.is_null() || buf.is_null() {
+        return;
+    }
+
+    let ram: &[std::os::raw::c_uchar] = std::slice::from_raw_parts(buf, len);
+    (&mut *gb).load_ram(ram);
+}
+
+#[repr(C)]
+#[derive(Debug, Eq, PartialEq)]
+pub enum StateStatus {
+    Ok = 0,
+    NullPointer = 1,
+    BufferTooSmall = 2,
+    InvalidState = 3,
+    UnsupportedVersion = 4,
+    CartridgeMismatch = 5,
+    Panic = 6,
+}
+
+impl From<StateError> for StateStatus {
+    fn from(err: StateError) -> Self {
+        match err {
+            StateError::UnsupportedVersion(_) => StateStatus::UnsupportedVersion,
+            StateError::CartridgeMismatch => StateStatus::CartridgeMismatch,
+            StateError::InvalidHeader | StateError::UnexpectedEnd | StateError::InvalidValue => {
+                StateStatus::InvalidState
+            }
+        }
+    }
+}
+
+// len always receives the size of the state, pass a null buf to find out how big it needs to be
+#[no_mangle]
+pub unsafe extern "C" fn gb_save_state(
+    gb: *mut Console,
+    buf: *mut std::os::raw::c_uchar,
+    len: *mut usize,
+) -> StateStatus {
+    if gb.is_null() || len.is_null() {
+        return StateStatus::NullPointer;
+    }
+
+    let state = (&*gb).save_state();
+    let capacity = *len;
+    *len = state.len();
+
+    if buf.is_null() {
+        return StateStatus::Ok;
+    }
+
+    if capacity < state.len() {
+        return StateStatus::BufferTooSmall;
+    }
+
+    let buf: &mut [std::os::raw::c_uchar] = std::slice::from_raw_parts_mut(buf, state.len());
+    buf.copy_from_slice(&state);
+
+    StateStatus::Ok
+}
+
+// the console is left untouched if the state can't be loaded
+#[no_mangle]
+pub unsafe extern "C" fn gb_load_state(
+    gb: *mut Console,
+    buf: *const std::os::raw::c_uchar,
+    len: usize,
+) -> StateStatus {
+    if gb.is_null() || buf.is_null() {
+        return StateStatus::NullPointer;
+    }
+
+    let state: &[std::os::raw::c_uchar] = std::slice::from_raw_parts(buf, len);
+
+    // unwinding across the extern "C" boundary is undefined behaviour
+    let result = panic::catch_unwind(AssertUnwindSafe(|| (&mut *gb).load_state(state)));
+
+    match result {
+        Ok(Ok(())) => StateStatus::Ok,
+        Ok(Err(err)) => StateStatus::from(err),
+        Err(_) => StateStatus::Panic,
+    }
+}
+
+// out must hold max_samples interleaved stereo samples, returns the number copied
+#[no_mangle]
+pub unsafe extern "C" fn gb_get_audio_samples(
+    gb: *mut Console,
+    out: *mut f32,
+    max_samples: usize,
+) -> usize {
+    if gb.is_null() || out.is_null() {
+        return 0;
+    }
+
+    // never build a slice bigger than the samples that are actually available
+    let samples = max_samples.min((&*gb).audio_samples().len() / 2);
+
+    let out: &mut [f32] = std::slice::from_raw_parts_mut(out, samples * 2);
+    (&mut *gb).take_audio_samples(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{console, scrolling_console};
+    use std::ffi::CString;
+    use std::ptr;
+
+    unsafe fn save_state(gb: *mut Console) -> Vec<u8> {
+        let mut len = 0;
+        assert_eq!(
+            gb_save_state(gb, ptr::null_mut(), &mut len),
+            StateStatus::Ok
+        );
+
+        let mut state = vec![0; len];
+        assert_eq!(
+            gb_save_state(gb, state.as_mut_ptr(), &mut len),
+            StateStatus::Ok
+        );
+        assert_eq!(len, state.len());
+
+        state
+    }
+
+    unsafe fn frame_buffer(gb: *mut Console) -> Vec<u8> {
+        let mut buf = vec![0; 160 * 144];
+        gb_get_frame_buffer(gb, buf.as_mut_ptr());
+        buf
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        unsafe {
+            let original = Box::into_raw(Box::new(scrolling_console()));
+            let restored = Box::into_raw(Box::new(scrolling_console()));
+
+            for _ in 0..3 {
+                gb_run_frame(original);
+            }
+
+            let state = save_state(original);
+            assert_eq!(
+                gb_load_state(restored, state.as_ptr(), state.len()),
+                StateStatus::Ok
+            );
+
+            for _ in 0..3 {
+                gb_run_frame(original);
+                gb_run_frame(restored);
+                assert_eq!(frame_buffer(restored), frame_buffer(original));
+            }
+
+            gb_destroy(original);
+            gb_destroy(restored);
+        }
+    }
+
+    #[test]
+    fn invalid_state_leaves_console_untouched() {
+        unsafe {
+            let gb = Box::into_raw(Box::new(scrolling_console()));
+            gb_run_frame(gb);
+
+            let before = save_state(gb);
+            let mut state = before.clone();
+            state.truncate(state.len() / 2);
+
+            assert_eq!(
+                gb_load_state(gb, state.as_ptr(), state.len()),
+                StateStatus::InvalidState
+            );
+            assert_eq!(save_state(gb), before);
+
+            let mut len = 1;
+            let mut buf = [0];
+            assert_eq!(
+                gb_save_state(gb, buf.as_mut_ptr(), &mut len),
+                StateStatus::BufferTooSmall
+            );
+            assert_eq!(len, before.len());
+
+            gb_destroy(gb);
+        }
+    }
+
+    #[test]
+    fn missing_rom_is_an_io_error() {
+        unsafe {
+            let gb = gb_create();
+            let path = CString::new("does/not/exist.gb").unwrap();
+
+            assert_eq!(
+                gb_load_rom(gb, path.as_ptr(), ptr::null_mut(), 0),
+                LoadStatus::IOError
+            );
+
+            gb_destroy(gb);
+        }
+    }
+
+    #[test]
+    fn unsupported_cartridge_leaves_title_untouched() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x138].copy_from_slice(b"TEST");
+        rom[0x147] = 0xFF;
+
+        let mut title = [0x7F; 16];
+
+        unsafe {
+            let gb = gb_create();
+
+            assert_eq!(
+                gb_load_rom_bytes(gb, rom.as_ptr(), rom.len(), title.as_mut_ptr(), title.len()),
+                LoadStatus::UnsupportedCartridge
+            );
+            assert_eq!(title, [0x7F; 16]);
+
+            rom[0x147] = 0x00;
+            assert_eq!(
+                gb_load_rom_bytes(gb, rom.as_ptr(), rom.len(), title.as_mut_ptr(), title.len()),
+                LoadStatus::Ok
+            );
+            assert_eq!(CStr::from_ptr(title.as_ptr()).to_bytes(), b"TEST");
+
+            gb_destroy(gb);
+        }
+    }
+
+    #[test]
+    fn null_pointers_are_ignored() {
+        unsafe {
+            let gb = gb_create();
+
+            gb_run_frame(ptr::null_mut());
+            gb_get_frame_buffer(gb, ptr::null_mut());
+            gb_get_frame_buffer_rgba(gb, ptr::null(), ptr::null_mut());
+            assert_eq!(gb_get_ram_size(ptr::null_mut()), 0);
+            assert_eq!(gb_dump_ram(gb, ptr::null_mut(), 0), 0);
+            gb_load_ram(gb, ptr::null(), 0);
+            gb_destroy(ptr::null_mut());
+
+            gb_destroy(gb);
+        }
+    }
+
+    #[test]
+    fn pull_audio_samples() {
+        let mut console = console(&[0x18, 0xFE], false); // JR -2
+
+        // channel 1 at full volume to both outputs
+        for &(address, value) in &[(0xFF26, 0x80), (0xFF24, 0x77), (0xFF25, 0xFF)] {
+            console.write(address, value);
+        }
+        console.write(0xFF12, 0xF0);
+        console.write(0xFF14, 0x80);
+
+        unsafe {
+            let gb = Box::into_raw(Box::new(console));
+            gb_run_frame(gb);
+
+            // the copy is capped at the capacity given
+            let mut out = vec![0.0; 4096 * 2];
+            assert_eq!(gb_get_audio_samples(gb, out.as_mut_ptr(), 10), 10);
+            assert!(out[..20].iter().any(|&sample| sample != 0.0));
+            assert!(out[20..].iter().all(|&sample| sample == 0.0));
+
+            // the rest of the frame's samples are left for the next call
+            let samples = gb_get_audio_samples(gb, out.as_mut_ptr(), 4096);
+            assert!(samples > 0);
+            assert_eq!(gb_get_audio_samples(gb, out.as_mut_ptr(), 4096), 0);
+
+            assert_eq!(gb_get_audio_samples(gb, ptr::null_mut(), 4096), 0);
+
+            gb_destroy(gb);
+        }
+    }
+}
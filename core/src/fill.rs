@@ -0,0 +1,88 @@
+// the contents of RAM at power on, which real hardware leaves uninitialised
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RamFill {
+    Zero,
+    // the same seed always gives the same contents
+    Random(u64),
+    // alternating runs of 0x00 and 0xFF, as often seen on a DMG
+    Checkerboard,
+}
+
+impl RamFill {
+    // fills each RAM in turn, random fills carry on from one RAM to the next
+    pub fn fill(self, rams: &mut [&mut [u8]]) {
+        // xorshift can't leave a zero state, so the seed is mixed with a non-zero constant
+        let mut state = match self {
+            RamFill::Random(seed) => (seed ^ 0x9E37_79B9_7F4A_7C15).max(1),
+            _ => 0,
+        };
+
+        for ram in rams.iter_mut() {
+            for (i, byte) in ram.iter_mut().enumerate() {
+                *byte = match self {
+                    RamFill::Zero => 0x00,
+                    RamFill::Random(_) => {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+
+                        (state >> 32) as u8
+                    }
+                    RamFill::Checkerboard if i / 16 % 2 == 0 => 0x00,
+                    RamFill::Checkerboard => 0xFF,
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Console;
+
+    fn filled(fill: RamFill) -> Vec<u8> {
+        let mut first = [0; 64];
+        let mut second = [0; 64];
+        fill.fill(&mut [&mut first, &mut second]);
+
+        [first, second].concat()
+    }
+
+    #[test]
+    fn random_fill_is_reproducible() {
+        assert_eq!(filled(RamFill::Random(42)), filled(RamFill::Random(42)));
+        assert_ne!(filled(RamFill::Random(42)), filled(RamFill::Random(43)));
+
+        // the second RAM carries on from the first rather than repeating it
+        let ram = filled(RamFill::Random(42));
+        assert_ne!(ram[..64], ram[64..]);
+    }
+
+    #[test]
+    fn checkerboard_fill() {
+        let ram = filled(RamFill::Checkerboard);
+        assert_eq!(ram[..16], [0x00; 16]);
+        assert_eq!(ram[16..32], [0xFF; 16]);
+        assert_eq!(ram[64..80], [0x00; 16]);
+
+        assert_eq!(filled(RamFill::Zero), [0; 128]);
+    }
+
+    #[test]
+    fn console_ram_filled_at_power_on() {
+        let memory = |fill| {
+            let mut console = Console::with_ram_fill(fill);
+            console.load_rom_bytes(&[0; 0x8000]).unwrap();
+
+            [0x8000, 0xC000, 0xDFFF, 0xFE00, 0xFF80]
+                .iter()
+                .map(|&address| console.peek(address))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(memory(RamFill::Random(7)), memory(RamFill::Random(7)));
+        assert_ne!(memory(RamFill::Random(7)), memory(RamFill::Random(8)));
+        assert_eq!(memory(RamFill::Zero), [0; 5]);
+    }
+}
@@ -0,0 +1,45 @@
+use alloc::vec::{self, Vec};
+
+// the buttons held at the start of each frame, one bit per Button
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InputLog {
+    frames: Vec<u8>,
+}
+
+impl InputLog {
+    pub fn new() -> Self {
+        InputLog { frames: Vec::new() }
+    }
+
+    // one byte per frame, as produced by as_bytes
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        InputLog {
+            frames: bytes.to_vec(),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.frames
+    }
+
+    pub fn push(&mut self, buttons: u8) {
+        self.frames.push(buttons);
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+impl IntoIterator for InputLog {
+    type Item = u8;
+    type IntoIter = vec::IntoIter<u8>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.frames.into_iter()
+    }
+}
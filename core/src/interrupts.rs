@@ -1,3 +1,5 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
 #[repr(u8)]
 pub enum Interrupt {
     VBlank,
@@ -31,7 +33,22 @@ impl Interrupts {
 }
 
 impl Interrupts {
-    pub fn request(&mut self, interrupt: Interrupt) {
-        self.r#if |= u8::from(interrupt);
+    // requests every interrupt set in the mask
+    pub fn request(&mut self, interrupts: u8) {
+        self.r#if |= interrupts;
+    }
+}
+
+impl Snapshot for Interrupts {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.r#if);
+        state.write_u8(self.ie);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.r#if = state.read_u8()?;
+        self.ie = state.read_u8()?;
+
+        Ok(())
     }
 }
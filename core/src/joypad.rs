@@ -0,0 +1,290 @@
+use crate::interrupts::Interrupt;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use bit_field::BitField;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Button {
+    Right = 0,
+    Left = 1,
+    Up = 2,
+    Down = 3,
+    A = 4,
+    B = 5,
+    Select = 6,
+    Start = 7,
+}
+
+// auto-fire, a held button is reported pressed for `on` frames then released for `off` frames
+#[derive(Clone, Copy)]
+struct Turbo {
+    on: usize,
+    off: usize,
+    frame: usize,
+}
+
+pub struct Joypad {
+    // what the game sees
+    buttons: u8,
+    // what the frontend is holding
+    held: u8,
+    select: u8,
+    turbo: [Option<Turbo>; 8],
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            buttons: 0,
+            held: 0,
+            select: 0x30,
+            turbo: [None; 8],
+        }
+    }
+}
+
+impl Joypad {
+    pub fn read_byte(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    // selecting a group with a button already held also pulls a line low
+    pub fn write_byte(&mut self, value: u8) -> u8 {
+        let lines = self.lines();
+        self.select = value & 0x30;
+
+        self.interrupts(lines)
+    }
+
+    // the turbo settings are kept
+    pub fn reset(&mut self) {
+        self.buttons = 0;
+        self.held = 0;
+        self.select = 0x30;
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> u8 {
+        let lines = self.lines();
+        self.held.set_bit(button as usize, pressed);
+        self.buttons.set_bit(button as usize, pressed);
+
+        // a turbo button starts on the pressed part of its cycle
+        if let Some(turbo) = &mut self.turbo[button as usize] {
+            turbo.frame = 0;
+        }
+
+        self.interrupts(lines)
+    }
+
+    // every button at once, bit n is the Button with value n
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) -> u8 {
+        let lines = self.lines();
+        self.held = buttons;
+        self.buttons = buttons;
+
+        self.interrupts(lines)
+    }
+
+    pub fn set_turbo(&mut self, button: Button, turbo: Option<(usize, usize)>) {
+        self.turbo[button as usize] = turbo.map(|(on, off)| Turbo { on, off, frame: 0 });
+    }
+
+    // called once per frame, toggles the reported state of held turbo buttons
+    pub fn step_frame(&mut self) -> u8 {
+        let lines = self.lines();
+
+        for (index, turbo) in self.turbo.iter_mut().enumerate() {
+            let turbo = match turbo {
+                Some(turbo) if self.held.get_bit(index) => turbo,
+                _ => continue,
+            };
+
+            let period = (turbo.on + turbo.off).max(1);
+            self.buttons.set_bit(index, turbo.frame % period < turbo.on);
+            turbo.frame = (turbo.frame + 1) % period;
+        }
+
+        self.interrupts(lines)
+    }
+}
+
+impl Joypad {
+    // the lines are active low, a pressed button in a selected group reads as 0
+    fn lines(&self) -> u8 {
+        let mut lines = 0x0F;
+
+        if !self.select.get_bit(4) {
+            lines &= !self.buttons.get_bits(0..4);
+        }
+
+        if !self.select.get_bit(5) {
+            lines &= !self.buttons.get_bits(4..8);
+        }
+
+        lines
+    }
+
+    // the interrupt is requested when any line goes from high to low
+    fn interrupts(&self, previous_lines: u8) -> u8 {
+        if previous_lines & !self.lines() != 0 {
+            u8::from(Interrupt::Joypad)
+        } else {
+            0
+        }
+    }
+}
+
+impl Snapshot for Joypad {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.buttons);
+        state.write_u8(self.select);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = state.read_u8()?;
+        self.held = self.buttons;
+        self.select = state.read_u8()? & 0x30;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_button_groups() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Right, true);
+        joypad.set_button(Button::Start, true);
+
+        // nothing selected
+        assert_eq!(joypad.read_byte(), 0xFF);
+
+        // P14 low selects the directions
+        joypad.write_byte(0x20);
+        assert_eq!(joypad.read_byte(), 0xEE);
+
+        // P15 low selects the action buttons
+        joypad.write_byte(0x10);
+        assert_eq!(joypad.read_byte(), 0xD7);
+
+        // both groups at once are ANDed together
+        joypad.write_byte(0x00);
+        assert_eq!(joypad.read_byte(), 0xC6);
+    }
+
+    #[test]
+    fn interrupt_on_high_to_low() {
+        let mut joypad = Joypad::new();
+        joypad.write_byte(0x10);
+
+        // the directions aren't selected, so their lines stay high
+        assert_eq!(joypad.set_button(Button::Up, true), 0);
+
+        assert_eq!(
+            joypad.set_button(Button::B, true),
+            u8::from(Interrupt::Joypad)
+        );
+        assert_eq!(joypad.set_button(Button::B, false), 0);
+
+        // selecting a group with a button already held pulls its line low
+        assert_eq!(joypad.write_byte(0x20), u8::from(Interrupt::Joypad));
+    }
+
+    // whether A is reported pressed after each of the next frames, and the frames that requested
+    // an interrupt
+    fn cadence(joypad: &mut Joypad, frames: usize) -> (Vec<bool>, Vec<usize>) {
+        let mut pressed = vec![];
+        let mut interrupts = vec![];
+
+        for frame in 0..frames {
+            if joypad.step_frame() & u8::from(Interrupt::Joypad) != 0 {
+                interrupts.push(frame);
+            }
+
+            pressed.push(joypad.buttons().get_bit(Button::A as usize));
+        }
+
+        (pressed, interrupts)
+    }
+
+    fn turbo_joypad() -> Joypad {
+        let mut joypad = Joypad::new();
+        joypad.write_byte(0x10); // select the action buttons
+        joypad.set_turbo(Button::A, Some((2, 1)));
+        joypad
+    }
+
+    #[test]
+    fn turbo_cadence() {
+        let mut joypad = turbo_joypad();
+        joypad.set_button(Button::B, true);
+        joypad.set_button(Button::A, true);
+
+        let (pressed, interrupts) = cadence(&mut joypad, 9);
+        assert_eq!(
+            pressed,
+            [true, true, false, true, true, false, true, true, false]
+        );
+
+        // B is held throughout, the line for A only goes low again as it's repressed
+        assert!(joypad.buttons().get_bit(Button::B as usize));
+        assert_eq!(interrupts, [3, 6]);
+    }
+
+    #[test]
+    fn turbo_stops_on_release() {
+        let mut joypad = turbo_joypad();
+        joypad.set_button(Button::A, true);
+        cadence(&mut joypad, 4);
+
+        joypad.set_button(Button::A, false);
+        assert_eq!(cadence(&mut joypad, 3), (vec![false; 3], vec![]));
+    }
+
+    #[test]
+    fn turbo_survives_reset() {
+        let mut joypad = turbo_joypad();
+        joypad.set_button(Button::A, true);
+        cadence(&mut joypad, 2);
+
+        joypad.reset();
+        assert_eq!(joypad.buttons(), 0);
+
+        // pressing again starts from the beginning of the cycle
+        joypad.write_byte(0x10);
+        joypad.set_button(Button::A, true);
+        assert_eq!(cadence(&mut joypad, 3).0, [true, true, false]);
+    }
+
+    #[test]
+    fn interrupt_only_for_selected_group() {
+        let interrupt = u8::from(Interrupt::Joypad);
+
+        // P14 low, the directions only
+        let mut directions = Joypad::new();
+        directions.write_byte(0x20);
+        assert_eq!(directions.set_button(Button::A, true), 0);
+        assert_eq!(directions.set_button(Button::Down, true), interrupt);
+        assert_eq!(directions.read_byte(), 0xE7);
+
+        // P15 low, the action buttons only
+        let mut actions = Joypad::new();
+        actions.write_byte(0x10);
+        assert_eq!(actions.set_button(Button::Down, true), 0);
+        assert_eq!(actions.set_button(Button::A, true), interrupt);
+        assert_eq!(actions.read_byte(), 0xDE);
+
+        // with neither selected every line reads high and nothing interrupts
+        let mut neither = Joypad::new();
+        neither.write_byte(0x30);
+        assert_eq!(neither.set_button(Button::Down, true), 0);
+        assert_eq!(neither.set_button(Button::A, true), 0);
+        assert_eq!(neither.read_byte(), 0xFF);
+    }
+}
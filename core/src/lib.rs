@@ -1,128 +1,1481 @@
+// without the std feature the core only needs alloc, the core_no_std test builds it that way
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+mod apu;
 mod bus;
 mod cartridge;
+mod cheats;
 mod cpu;
+mod dma;
+#[cfg(feature = "std")]
 mod ffi;
+mod fill;
+mod input;
 mod interrupts;
+mod joypad;
+mod link;
 mod rom;
 mod serial;
+mod speed;
+mod state;
 mod timer;
+mod trace;
 mod video;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod wram;
 
+use apu::APU;
 use bus::AddressBus;
 use cartridge::Cartridge;
+use cheats::Cheats;
 use cpu::CPU;
+use dma::DMA;
 use interrupts::Interrupts;
+use joypad::Joypad;
+use rom::BootROM;
 use serial::Serial;
+use speed::Speed;
+use state::{Snapshot, StateReader, StateWriter};
 use timer::Timer;
 use video::Video;
+use wram::WRAM;
+
+use alloc::{boxed::Box, rc::Rc, string::String, vec, vec::Vec};
+use core::cell::RefCell;
+use core::convert::TryFrom;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+pub use bus::{Access, UnmappedAccess, UnmappedLogger};
+pub use cartridge::{CartridgeError, CartridgeInfo};
+pub use cheats::CheatError;
+pub use cpu::{CpuState, Flag, Model, Registers};
+pub use fill::RamFill;
+pub use input::InputLog;
+pub use joypad::Button;
+pub use link::LinkCable;
+pub use rom::{CartridgeType, ROM};
+pub use state::StateError;
+pub use timer::TimerState;
+pub use trace::{TraceLine, TraceSink};
+pub use video::{Mode, PalettePreset, DMG_PALETTE};
+#[cfg(feature = "wasm")]
+pub use wasm::WasmConsole;
 
 const CPU_CYCLES_PER_FRAME: usize = 70_224;
 
+// everything a frontend needs after running up to a frame boundary
+pub struct FrameOutput {
+    // RGBA, 160x144
+    pub framebuffer: Vec<u8>,
+    // the number of new interleaved stereo samples available from audio_samples
+    pub audio_samples: usize,
+    pub frame_ready: bool,
+}
+
 pub struct Console {
     cpu: CPU,
+    boot_rom: Option<BootROM>,
     cartridge: Option<Cartridge>,
-    wram: [u8; 8192],
+    wram: WRAM,
+    joypad: Joypad,
     serial: Serial,
     timer: Timer,
+    apu: APU,
     video: Video,
+    dma: DMA,
     interrupts: Interrupts,
     hram: [u8; 127],
+    speed: Speed,
+    cheats: Cheats,
+    stalled: bool,
+    model: Model,
+    ram_fill: RamFill,
+    recording: Option<InputLog>,
+    playback: Option<vec::IntoIter<u8>>,
+    unmapped_logger: Option<UnmappedLogger>,
+    palette: [[u8; 4]; 4],
+    frame_surplus: usize,
+    cycle_surplus: usize,
 }
 
 impl Console {
     fn new() -> Self {
         Console {
             cpu: CPU::new(),
+            boot_rom: None,
             cartridge: None,
-            wram: [0; 8192],
+            wram: WRAM::new(),
+            joypad: Joypad::new(),
             serial: Serial::new(),
             timer: Timer::new(),
+            apu: APU::new(),
             video: Video::new(),
+            dma: DMA::new(),
             interrupts: Interrupts::new(),
             hram: [0; 127],
+            speed: Speed::new(),
+            cheats: Cheats::new(),
+            stalled: false,
+            model: Model::Dmg,
+            ram_fill: RamFill::Zero,
+            recording: None,
+            playback: None,
+            unmapped_logger: None,
+            palette: DMG_PALETTE,
+            frame_surplus: 0,
+            cycle_surplus: 0,
+        }
+    }
+
+    pub fn with_model(model: Model) -> Self {
+        Console {
+            cpu: CPU::new_with_model(model),
+            model,
+            ..Console::new()
+        }
+    }
+
+    pub fn with_ram_fill(ram_fill: RamFill) -> Self {
+        let mut console = Console {
+            ram_fill,
+            ..Console::new()
+        };
+
+        console.fill_ram();
+        console
+    }
+
+    pub fn with_boot_rom(boot_rom: &[u8]) -> Self {
+        Console {
+            cpu: CPU::new_with_model(Model::Zeroed),
+            model: Model::Zeroed,
+            boot_rom: Some(BootROM::new(boot_rom)),
+            ..Console::new()
         }
     }
 }
 
 impl Console {
     fn insert_cartridge(&mut self, cartridge: Cartridge) {
+        self.video.set_cgb_mode(cartridge.supports_cgb());
+        self.wram.set_cgb_mode(cartridge.supports_cgb());
+        self.serial.set_cgb_mode(cartridge.supports_cgb());
+        self.speed.set_cgb_mode(cartridge.supports_cgb());
         self.cartridge = Some(cartridge);
 
+        // the boot ROM initialises the hardware itself, a zeroed start leaves it at power-on
+        if self.boot_rom.is_some() || self.model == Model::Zeroed {
+            return;
+        }
+
+        let mut bus = self.bus().unwrap();
+
+        // the APU has to be powered on before its registers can be written
+        bus.poke_byte(0xFF26, 0xF1);
+
+        bus.poke_byte(0xFF05, 0x00);
+        bus.poke_byte(0xFF06, 0x00);
+        bus.poke_byte(0xFF07, 0x00);
+        bus.poke_byte(0xFF10, 0x80);
+        bus.poke_byte(0xFF11, 0xBF);
+        bus.poke_byte(0xFF12, 0xF3);
+        bus.poke_byte(0xFF14, 0xBF);
+        bus.poke_byte(0xFF16, 0x3F);
+        bus.poke_byte(0xFF17, 0x00);
+        bus.poke_byte(0xFF19, 0xBF);
+        bus.poke_byte(0xFF1A, 0x7F);
+        bus.poke_byte(0xFF1B, 0xFF);
+        bus.poke_byte(0xFF1C, 0x9F);
+        bus.poke_byte(0xFF1E, 0xBF);
+        bus.poke_byte(0xFF20, 0xFF);
+        bus.poke_byte(0xFF21, 0x00);
+        bus.poke_byte(0xFF22, 0x00);
+        bus.poke_byte(0xFF23, 0xBF);
+        bus.poke_byte(0xFF24, 0x77);
+        bus.poke_byte(0xFF25, 0xF3);
+        bus.poke_byte(0xFF40, 0x91);
+        bus.poke_byte(0xFF42, 0x00);
+        bus.poke_byte(0xFF43, 0x00);
+        bus.poke_byte(0xFF45, 0x00);
+        bus.poke_byte(0xFF47, 0xFC);
+        bus.poke_byte(0xFF48, 0xFF);
+        bus.poke_byte(0xFF49, 0xFF);
+        bus.poke_byte(0xFF4A, 0x00);
+        bus.poke_byte(0xFF4B, 0x00);
+        bus.poke_byte(0xFFFF, 0x00);
+    }
+
+    // as insert_cartridge, for ROMs already in memory
+    pub fn load_rom_bytes(&mut self, bytes: &[u8]) -> Result<(), CartridgeError> {
+        let cartridge = Cartridge::try_from(ROM::from_bytes(bytes.to_vec()))?;
+        self.insert_cartridge(cartridge);
+
+        Ok(())
+    }
+
+    // what the inserted cartridge contains, if there is one
+    pub fn cartridge_info(&self) -> Option<CartridgeInfo> {
+        self.cartridge.as_ref().map(Cartridge::info)
+    }
+
+    // None until a cartridge has been inserted
+    fn bus(&mut self) -> Option<AddressBus<'_>> {
+        let cartridge = self.cartridge.as_mut()?;
+
+        Some(AddressBus::new(
+            &mut self.boot_rom,
+            cartridge,
+            &mut self.wram,
+            &mut self.joypad,
+            &mut self.serial,
+            &mut self.timer,
+            &mut self.apu,
+            &mut self.video,
+            &mut self.dma,
+            &mut self.interrupts,
+            &mut self.hram,
+            &mut self.speed,
+            &self.cheats,
+            &mut self.unmapped_logger,
+        ))
+    }
+
+    pub fn step(&mut self) -> usize {
+        let cartridge = match &mut self.cartridge {
+            Some(cartridge) => cartridge,
+            None => return 0,
+        };
+
         let mut bus = AddressBus::new(
-            self.cartridge.as_mut().unwrap(),
+            &mut self.boot_rom,
+            cartridge,
             &mut self.wram,
+            &mut self.joypad,
             &mut self.serial,
             &mut self.timer,
+            &mut self.apu,
             &mut self.video,
+            &mut self.dma,
             &mut self.interrupts,
             &mut self.hram,
+            &mut self.speed,
+            &self.cheats,
+            &mut self.unmapped_logger,
         );
 
-        bus.write_byte(0xFF05, 0x00);
-        bus.write_byte(0xFF06, 0x00);
-        bus.write_byte(0xFF07, 0x00);
-        bus.write_byte(0xFF10, 0x80);
-        bus.write_byte(0xFF11, 0xBF);
-        bus.write_byte(0xFF12, 0xF3);
-        bus.write_byte(0xFF14, 0xBF);
-        bus.write_byte(0xFF16, 0x3F);
-        bus.write_byte(0xFF17, 0x00);
-        bus.write_byte(0xFF19, 0xBF);
-        bus.write_byte(0xFF1A, 0x7F);
-        bus.write_byte(0xFF1B, 0xFF);
-        bus.write_byte(0xFF1C, 0x9F);
-        bus.write_byte(0xFF1E, 0xBF);
-        bus.write_byte(0xFF20, 0xFF);
-        bus.write_byte(0xFF21, 0x00);
-        bus.write_byte(0xFF22, 0x00);
-        bus.write_byte(0xFF23, 0xBF);
-        bus.write_byte(0xFF24, 0x77);
-        bus.write_byte(0xFF25, 0xF3);
-        bus.write_byte(0xFF26, 0xF1);
-        bus.write_byte(0xFF40, 0x91);
-        bus.write_byte(0xFF42, 0x00);
-        bus.write_byte(0xFF43, 0x00);
-        bus.write_byte(0xFF45, 0x00);
-        bus.write_byte(0xFF47, 0xFC);
-        bus.write_byte(0xFF48, 0xFF);
-        bus.write_byte(0xFF49, 0xFF);
-        bus.write_byte(0xFF4A, 0x00);
-        bus.write_byte(0xFF4B, 0x00);
-        bus.write_byte(0xFFFF, 0x00);
-    }
-
-    fn run_frame(&mut self) {
+        let pc = self.cpu.state().registers.pc;
+        let cycles = self.cpu.step(&mut bus);
+
+        // catch up on the cycles where the CPU didn't access the bus
+        bus.step(cycles.saturating_sub(bus.cycles()));
+
+        // a jump to itself can never be left without an interrupt
+        let state = self.cpu.state();
+        self.stalled = state.registers.pc == pc && !state.ime && !state.halt && !state.stopped;
+
+        cycles
+    }
+
+    // runs until the next VBlank, returning true if a new frame has been drawn
+    pub fn run_frame(&mut self) -> bool {
+        if self.cartridge.is_none() {
+            return false;
+        }
+
+        self.update_input();
+
+        // cycles run past the end of the previous frame count towards this one
+        let mut elapsed_cycles = self.frame_surplus;
+
+        // there are no VBlanks whilst the LCD is off, so stop after a frame's worth of cycles
+        while elapsed_cycles < CPU_CYCLES_PER_FRAME {
+            let cycles = self.step();
+
+            // a frame is measured in normal speed cycles
+            elapsed_cycles += if self.speed.double_speed() {
+                cycles / 2
+            } else {
+                cycles
+            };
+
+            if self.video.take_frame_ready() {
+                self.apply_gameshark_codes();
+
+                // the video keeps its own time, so there is nothing to carry over
+                self.frame_surplus = 0;
+
+                // a skipped frame still ends here, but leaves the framebuffer as it was
+                return !self.video.frame_skipped();
+            }
+        }
+
+        self.frame_surplus = elapsed_cycles - CPU_CYCLES_PER_FRAME;
+
+        false
+    }
+
+    fn fill_ram(&mut self) {
+        let mut vram = [0; 0x4000];
+        let mut oam = [0; 160];
+
+        self.ram_fill
+            .fill(&mut [self.wram.ram_mut(), &mut self.hram, &mut vram, &mut oam]);
+        self.video.fill_ram(&vram, &oam);
+    }
+
+    // runs whole instructions until the budget is used, returning how many cycles were run
+    pub fn run_cycles(&mut self, budget: usize) -> usize {
+        if self.cartridge.is_none() {
+            return 0;
+        }
+
+        // cycles run past the end of the previous budget count towards this one
         let mut elapsed_cycles = 0;
 
-        if let Some(cartridge) = &mut self.cartridge {
-            while elapsed_cycles <= CPU_CYCLES_PER_FRAME {
-                let mut bus = AddressBus::new(
-                    cartridge,
-                    &mut self.wram,
-                    &mut self.serial,
-                    &mut self.timer,
-                    &mut self.video,
-                    &mut self.interrupts,
-                    &mut self.hram,
-                );
-
-                let cycles = self.cpu.step(&mut bus);
-
-                let interrupts: Vec<_> = vec![
-                    self.serial.step(cycles),
-                    self.timer.step(cycles),
-                    self.video.step(cycles),
-                ]
-                .into_iter()
-                .flatten()
-                .collect();
-
-                for interrupt in interrupts {
-                    self.interrupts.request(interrupt);
+        while self.cycle_surplus + elapsed_cycles < budget {
+            let cycles = self.step();
+
+            // budgets are measured in normal speed cycles, as frames are
+            elapsed_cycles += if self.speed.double_speed() {
+                cycles / 2
+            } else {
+                cycles
+            };
+
+            if self.video.take_frame_ready() {
+                self.apply_gameshark_codes();
+            }
+        }
+
+        self.cycle_surplus = self.cycle_surplus + elapsed_cycles - budget;
+
+        elapsed_cycles
+    }
+
+    // replayed input replaces the buttons for the frame before it's recorded
+    fn update_input(&mut self) {
+        let interrupts = self.joypad.step_frame();
+        self.interrupts.request(interrupts);
+
+        if let Some(playback) = &mut self.playback {
+            match playback.next() {
+                Some(buttons) => {
+                    let interrupts = self.joypad.set_buttons(buttons);
+                    self.interrupts.request(interrupts);
                 }
+                None => self.playback = None,
+            }
+        }
+
+        if let Some(recording) = &mut self.recording {
+            recording.push(self.joypad.buttons());
+        }
+    }
+
+    fn apply_gameshark_codes(&mut self) {
+        let writes = self.cheats.ram_writes();
+
+        if let Some(mut bus) = self.bus() {
+            for (address, value) in writes {
+                bus.poke_byte(address, value);
+            }
+        }
+    }
+
+    // runs until the next VBlank, returning the picture and how much audio was produced
+    pub fn tick_frame(&mut self) -> FrameOutput {
+        let samples = self.apu.samples().len();
+        let frame_ready = self.run_frame();
+
+        FrameOutput {
+            framebuffer: self.framebuffer_rgba(),
+            audio_samples: self.apu.samples().len().saturating_sub(samples) / 2,
+            frame_ready,
+        }
+    }
+
+    // n frames are skipped for every one drawn, the skipped ones leave the framebuffer untouched
+    pub fn set_frame_skip(&mut self, n: usize) {
+        self.video.set_frame_skip(n);
+    }
+
+    // runs n frames back to back for fast forwarding, only the last one is drawn
+    pub fn run_frames(&mut self, n: usize) -> Vec<u8> {
+        self.video.set_rendering(false);
+
+        for _ in 1..n {
+            self.run_frame();
+        }
+
+        self.video.set_rendering(true);
+
+        if n > 0 {
+            self.run_frame();
+        }
+
+        self.framebuffer_rgba()
+    }
+
+    // returns to the power-on state, the cartridge's battery RAM is kept
+    pub fn reset(&mut self) {
+        let trace = self.cpu.take_trace();
+        let hblank_callback = self.video.take_hblank_callback();
+        let frame_skip = self.video.frame_skip();
+
+        self.cpu = match &mut self.boot_rom {
+            Some(boot_rom) => {
+                boot_rom.mapped = true;
+                CPU::new_with_model(Model::Zeroed)
+            }
+            None => CPU::new_with_model(self.model),
+        };
+
+        self.cpu.set_trace(trace);
+
+        self.wram = WRAM::new();
+        self.joypad.reset();
+        self.serial.reset();
+        self.timer = Timer::new();
+        self.apu = APU::new();
+        self.video = Video::new();
+        self.video.set_hblank_callback(hblank_callback);
+        self.video.set_frame_skip(frame_skip);
+        self.dma = DMA::new();
+        self.interrupts = Interrupts::new();
+        self.hram = [0; 127];
+        self.speed = Speed::new();
+        self.stalled = false;
+        self.frame_surplus = 0;
+        self.cycle_surplus = 0;
+
+        self.fill_ram();
+
+        if let Some(mut cartridge) = self.cartridge.take() {
+            cartridge.reset();
+            self.insert_cartridge(cartridge);
+        }
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.speed.double_speed()
+    }
+
+    // sink receives the CPU state before each instruction, format it for a Gameboy Doctor log
+    pub fn set_trace(&mut self, sink: TraceSink) {
+        self.cpu.set_trace(Some(sink));
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.cpu.set_trace(None);
+    }
+
+    // callback receives LY as each line enters HBlank, for raster effects and debuggers
+    pub fn set_hblank_callback(&mut self, callback: Box<dyn FnMut(u8)>) {
+        self.video.set_hblank_callback(Some(callback));
+    }
+
+    pub fn clear_hblank_callback(&mut self) {
+        self.video.set_hblank_callback(None);
+    }
+
+    // reads the memory map without any time passing, reads 0xFF without a cartridge
+    pub fn read(&mut self, address: u16) -> u8 {
+        match self.bus() {
+            Some(bus) => bus.peek_byte(address),
+            None => 0xFF,
+        }
+    }
+
+    // writes go through the I/O registers, so writing DIV still resets it
+    pub fn write(&mut self, address: u16, value: u8) {
+        if let Some(mut bus) = self.bus() {
+            bus.poke_byte(address, value);
+        }
+    }
+
+    // as read, but sees VRAM and OAM even whilst the PPU has them locked
+    pub fn peek(&mut self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF | 0xFE00..=0xFE9F => self.video.peek_byte(address),
+            _ => self.read(address),
+        }
+    }
+
+    // patches reads from the cartridge ROM, e.g. 00A-17B-C49
+    pub fn add_genie_code(&mut self, code: &str) -> Result<(), CheatError> {
+        self.cheats.add_genie_code(code)
+    }
+
+    // writes a value into RAM at the end of every frame, e.g. 010138CD
+    pub fn add_gameshark_code(&mut self, code: &str) -> Result<(), CheatError> {
+        self.cheats.add_gameshark_code(code)
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+    }
+
+    // true when the last instruction jumped to itself with interrupts disabled
+    pub fn is_stalled(&self) -> bool {
+        self.stalled
+    }
+
+    pub fn ppu_mode(&self) -> Mode {
+        self.video.mode
+    }
+
+    pub fn current_line(&self) -> u8 {
+        self.video.ly
+    }
+
+    pub fn dot_in_line(&self) -> usize {
+        self.video.dot_in_line()
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    pub fn timer_state(&self) -> TimerState {
+        self.timer.state()
+    }
+
+    // four RGBA colours, from White to Black, used for the RGBA framebuffer
+    pub fn set_dmg_palette(&mut self, colors: [[u8; 4]; 4]) {
+        self.palette = colors;
+    }
+
+    pub fn set_palette_preset(&mut self, preset: PalettePreset) {
+        self.palette = preset.colours();
+    }
+
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        self.video.framebuffer_rgba(self.palette)
+    }
+
+    // the framebuffer as a binary PPM image, in the current palette or CGB colours
+    pub fn framebuffer_ppm(&self) -> Vec<u8> {
+        let mut ppm = b"P6\n160 144\n255\n".to_vec();
+
+        for pixel in self.framebuffer_rgba().chunks(4) {
+            ppm.extend_from_slice(&pixel[..3]);
+        }
+
+        ppm
+    }
+
+    #[cfg(feature = "std")]
+    pub fn export_ppm<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, self.framebuffer_ppm())
+    }
+
+    // independent of the DMG palette, for comparing frames in regression tests
+    pub fn frame_hash(&self) -> u64 {
+        self.video.frame_hash()
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let interrupts = self.joypad.set_button(button, pressed);
+
+        self.interrupts.request(interrupts);
+    }
+
+    // while held the button is reported pressed for frames_on frames then released for frames_off
+    pub fn set_turbo(&mut self, button: Button, frames_on: usize, frames_off: usize) {
+        self.joypad.set_turbo(button, Some((frames_on, frames_off)));
+    }
+
+    pub fn clear_turbo(&mut self, button: Button) {
+        self.joypad.set_turbo(button, None);
+    }
+
+    // logger receives every CPU read or write of an address nothing responds to
+    pub fn set_unmapped_access_logger(&mut self, logger: UnmappedLogger) {
+        self.unmapped_logger = Some(logger);
+    }
+
+    pub fn clear_unmapped_access_logger(&mut self) {
+        self.unmapped_logger = None;
+    }
+
+    // logs the buttons held at the start of every run_frame until stop_recording
+    pub fn start_recording(&mut self) {
+        self.recording = Some(InputLog::new());
+    }
+
+    pub fn stop_recording(&mut self) -> InputLog {
+        self.recording.take().unwrap_or_default()
+    }
+
+    // sets the buttons from the log at the start of every run_frame until it runs out
+    pub fn play_input(&mut self, log: InputLog) {
+        self.playback = Some(log.into_iter());
+    }
+
+    // called with every byte transferred out over the serial port
+    pub fn set_serial_callback<F: FnMut(u8) + 'static>(&mut self, callback: F) {
+        self.serial.set_callback(Box::new(callback));
+    }
+
+    // runs until the serial output contains the needle, for test ROMs which report over serial,
+    // failing if they print "Failed" or the frames run out
+    pub fn run_until_serial_text(
+        &mut self,
+        needle: &str,
+        max_frames: usize,
+    ) -> Result<String, String> {
+        let output = Rc::new(RefCell::new(Vec::new()));
+
+        let sink = Rc::clone(&output);
+        let callback = self
+            .serial
+            .replace_callback(Box::new(move |byte| sink.borrow_mut().push(byte)));
+
+        let mut result = Err(String::new());
+
+        for _ in 0..max_frames {
+            self.run_frame();
+
+            let text = String::from_utf8_lossy(&output.borrow()).into_owned();
+
+            if text.contains(needle) {
+                result = Ok(text);
+                break;
+            }
+
+            let failed = text.contains("Failed");
+            result = Err(text);
+
+            if failed {
+                break;
+            }
+        }
+
+        self.serial.set_callback(callback);
+
+        result
+    }
+
+    // the byte sent by a link cable peer, exchanged on the next transfer
+    pub fn receive_serial_byte(&mut self, value: u8) {
+        self.serial.receive_byte(value);
+    }
+
+    // interleaved stereo samples at 44100Hz produced since the last clear
+    pub fn audio_samples(&self) -> &[f32] {
+        self.apu.samples()
+    }
+
+    pub fn clear_audio_samples(&mut self) {
+        self.apu.clear_samples();
+    }
+
+    // pulls stereo samples into out, leaving any that don't fit for the next call
+    pub fn take_audio_samples(&mut self, out: &mut [f32]) -> usize {
+        self.apu.take_samples(out)
+    }
+
+    pub fn load_ram(&mut self, ram: &[u8]) {
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.load_ram(ram);
+        }
+    }
+
+    pub fn dump_ram(&self) -> Vec<u8> {
+        match &self.cartridge {
+            Some(cartridge) => cartridge.dump_ram(),
+            None => vec![],
+        }
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+
+        self.cpu.save(&mut state);
+        state.write_bool(self.boot_rom.as_ref().is_some_and(|b| b.mapped));
+        self.wram.save(&mut state);
+        self.joypad.save(&mut state);
+        self.serial.save(&mut state);
+        self.timer.save(&mut state);
+        self.apu.save(&mut state);
+        self.video.save(&mut state);
+        self.dma.save(&mut state);
+        self.interrupts.save(&mut state);
+        state.write_bytes(&self.hram);
+        self.speed.save(&mut state);
+        state.write_usize(self.frame_surplus);
+        state.write_usize(self.cycle_surplus);
+
+        state.write_bool(self.cartridge.is_some());
+        if let Some(cartridge) = &self.cartridge {
+            cartridge.save(&mut state);
+        }
 
-                elapsed_cycles += cycles;
+        state.into_bytes()
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(state)?;
+
+        // restore the current state if the new one turns out to be invalid part way through
+        let backup = self.save_state();
+
+        let result = self.read_state(&mut state);
+
+        if result.is_err() {
+            let mut backup = StateReader::new(&backup).unwrap();
+            self.read_state(&mut backup).unwrap();
+        }
+
+        result
+    }
+
+    fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.cpu.load(state)?;
+
+        let boot_rom_mapped = state.read_bool()?;
+        if let Some(boot_rom) = &mut self.boot_rom {
+            boot_rom.mapped = boot_rom_mapped;
+        }
+
+        self.wram.load(state)?;
+        self.joypad.load(state)?;
+        self.serial.load(state)?;
+        self.timer.load(state)?;
+        self.apu.load(state)?;
+        self.video.load(state)?;
+        self.dma.load(state)?;
+        self.interrupts.load(state)?;
+        state.read_bytes(&mut self.hram)?;
+        self.speed.load(state)?;
+        self.frame_surplus = state.read_usize()?;
+        self.cycle_surplus = state.read_usize()?;
+
+        match (state.read_bool()?, &mut self.cartridge) {
+            (true, Some(cartridge)) => cartridge.load(state),
+            (false, None) => Ok(()),
+            _ => Err(StateError::CartridgeMismatch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // a 32KB ROM only cartridge running program from the entry point
+    pub(crate) fn console(program: &[u8], cgb: bool) -> Console {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+
+        if cgb {
+            rom[0x143] = 0x80;
+        }
+
+        let mut console = Console::new();
+        console.load_rom_bytes(&rom).unwrap();
+        console
+    }
+
+    // arms the speed switch, starts the timer at 4096Hz and executes STOP
+    const SPEED_SWITCH: [u8; 12] = [
+        0x3E, 0x01, // LD A,0x01
+        0xE0, 0x4D, // LDH (KEY1),A
+        0x3E, 0x04, // LD A,0x04
+        0xE0, 0x07, // LDH (TAC),A
+        0x10, 0x00, // STOP
+        0x18, 0xFE, // JR -2
+    ];
+
+    // starts the timer at 4096Hz
+    const TIMER: [u8; 6] = [
+        0x3E, 0x04, // LD A,0x04
+        0xE0, 0x07, // LDH (TAC),A
+        0x18, 0xFE, // JR -2
+    ];
+
+    fn tima_per_frame(console: &mut Console) -> u8 {
+        let before = console.peek(0xFF05);
+        console.run_frame();
+        console.peek(0xFF05).wrapping_sub(before)
+    }
+
+    #[test]
+    fn double_speed_doubles_timer_rate() {
+        let mut normal = console(&TIMER, true);
+        let mut double = console(&SPEED_SWITCH, true);
+
+        normal.run_frame();
+        double.run_frame();
+
+        assert!(!normal.speed.double_speed());
+        assert!(double.speed.double_speed());
+
+        // 70224 cycles per frame at 1024 cycles per tick, twice as many CPU cycles in double speed
+        assert!((68..=69).contains(&tima_per_frame(&mut normal)));
+        assert!((137..=138).contains(&tima_per_frame(&mut double)));
+    }
+
+    #[test]
+    fn dmg_stop_ignores_key1() {
+        let mut console = console(&SPEED_SWITCH, false);
+        console.run_frame();
+
+        assert_eq!(console.peek(0xFF4D), 0xFF);
+        assert!(console.cpu_state().stopped);
+    }
+
+    // scrolls the background every few cycles, so every drawn frame differs from the last
+    const SCROLL: [u8; 5] = [
+        0x3C, // INC A
+        0xE0, 0x43, // LDH (SCX),A
+        0x18, 0xFB, // JR -5
+    ];
+
+    pub(crate) fn scrolling_console() -> Console {
+        console_with_background(&SCROLL)
+    }
+
+    fn console_with_background(program: &[u8]) -> Console {
+        let mut console = console(program, false);
+
+        // a background of varied tiles
+        console.write(0xFF40, 0x00);
+        for i in 0..0x1800 {
+            console.write(0x8000 + i, (u32::from(i) * 37 % 251) as u8);
+        }
+        for i in 0..0x400 {
+            console.write(0x9800 + i, (i % 7) as u8);
+        }
+        console.write(0xFF47, 0xE4);
+        console.write(0xFF40, 0x91);
+
+        console
+    }
+
+    #[test]
+    fn frame_skip_keeps_timing() {
+        let mut console = scrolling_console();
+        console.set_frame_skip(1);
+
+        let mut framebuffer = console.framebuffer_rgba();
+
+        for frame in 0..8 {
+            assert_eq!(console.run_frame(), frame % 2 == 0);
+
+            let drawn = console.framebuffer_rgba();
+            assert_eq!(drawn != framebuffer, frame % 2 == 0);
+            framebuffer = drawn;
+
+            // LY keeps advancing through skipped frames
+            assert_eq!(console.current_line(), 144);
+            console.run_cycles(1000);
+            assert_eq!(console.current_line(), 146);
+        }
+    }
+
+    #[test]
+    fn stop_clears_div_until_joypad() {
+        let mut program = vec![0x3E, 0x10, 0xE0, 0x00]; // LD A,0x10; LDH (P1),A
+        program.extend_from_slice(&[0x00; 100]); // NOP
+        program.extend_from_slice(&[0x10, 0x00, 0x18, 0xFE]); // STOP; JR -2
+
+        let mut console = console(&program, false);
+
+        for _ in 0..102 {
+            console.step();
+        }
+        assert_ne!(console.peek(0xFF04), 0);
+
+        console.step();
+        let state = console.cpu_state();
+        assert!(state.stopped);
+        assert_eq!(state.registers.pc, 0x16A);
+        assert_eq!(console.peek(0xFF04), 0);
+
+        // stays stopped until a selected joypad line goes low
+        for _ in 0..10 {
+            assert_eq!(console.step(), 4);
+        }
+        assert!(console.cpu_state().stopped);
+
+        console.set_button(Button::A, true);
+        console.step();
+        assert!(!console.cpu_state().stopped);
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut original = scrolling_console();
+        for _ in 0..3 {
+            original.run_frame();
+        }
+
+        let state = original.save_state();
+
+        let mut restored = scrolling_console();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+
+        // both carry on identically from the saved point
+        for _ in 0..3 {
+            original.run_frame();
+            restored.run_frame();
+            assert_eq!(restored.framebuffer_rgba(), original.framebuffer_rgba());
+        }
+
+        assert_eq!(restored.save_state(), original.save_state());
+    }
+
+    // sends each byte of text over serial with the internal clock, waiting for each to finish
+    fn serial_output(text: &[u8]) -> Vec<u8> {
+        // jump over the header, which is left blank
+        let mut program = vec![0xC3, 0x50, 0x01]; // JP 0x0150
+        program.resize(0x50, 0x00);
+
+        for &byte in text {
+            program.extend_from_slice(&[
+                0x3E, byte, // LD A,byte
+                0xE0, 0x01, // LDH (SB),A
+                0x3E, 0x81, // LD A,0x81
+                0xE0, 0x02, // LDH (SC),A
+                0xF0, 0x02, // LDH A,(SC)
+                0xCB, 0x7F, // BIT 7,A
+                0x20, 0xFA, // JR NZ,-6
+            ]);
+        }
+
+        program.extend_from_slice(&[0x18, 0xFE]); // JR -2
+        program
+    }
+
+    #[test]
+    fn serial_callback_receives_output() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+
+        let mut console = console(&serial_output(b"Passed"), false);
+        console.set_serial_callback({
+            let output = Rc::clone(&output);
+            move |byte| output.borrow_mut().push(byte)
+        });
+
+        // a byte takes 4096 cycles at 8192Hz, so all six fit in a frame
+        console.run_frame();
+
+        assert_eq!(&output.borrow()[..], b"Passed");
+    }
+
+    #[test]
+    fn run_until_serial_text() {
+        let mut failing = console(&serial_output(b"1 Failed"), false);
+        assert_eq!(
+            failing.run_until_serial_text("Passed", 10),
+            Err(String::from("1 Failed"))
+        );
+
+        let mut passing = console(&serial_output(b"Passed"), false);
+        assert_eq!(
+            passing.run_until_serial_text("Passed", 10),
+            Ok(String::from("Passed"))
+        );
+    }
+
+    #[test]
+    fn boot_rom_unmapped_by_ff50() {
+        let mut boot_rom = vec![0x00; 0x100];
+        boot_rom[..4].copy_from_slice(&[
+            0x3E, 0x01, // LD A,0x01
+            0xE0, 0x50, // LDH (0xFF50),A
+        ]);
+
+        let mut rom = vec![0x11; 0x8000];
+        rom[0x147..0x14A].copy_from_slice(&[0x00; 3]);
+
+        let mut console = Console::with_boot_rom(&boot_rom);
+        console.load_rom_bytes(&rom).unwrap();
+
+        // the boot ROM starts from zeroed registers
+        let registers = console.cpu_state().registers;
+        assert_eq!((registers.a, registers.pc, registers.sp), (0, 0, 0));
+
+        assert_eq!(console.peek(0x0000), 0x3E);
+        assert_eq!(console.peek(0x00FF), 0x00);
+        assert_eq!(console.peek(0x0100), 0x11);
+
+        console.step();
+        console.step();
+
+        assert_eq!(console.cpu_state().registers.pc, 0x0004);
+        assert_eq!(console.peek(0x0000), 0x11);
+        assert_eq!(console.peek(0x00FF), 0x11);
+    }
+
+    #[test]
+    fn cpu_state_after_step() {
+        let mut console = console(&[0x01, 0x34, 0x12], false); // LD BC,0x1234
+
+        let before = console.cpu_state();
+        assert_eq!(before.registers.pc, 0x100);
+        assert!(!before.halt && !before.stopped);
+
+        console.step();
+
+        let after = console.cpu_state();
+        assert_eq!(after.registers.pc, 0x103);
+        assert_eq!((after.registers.b, after.registers.c), (0x12, 0x34));
+        assert_eq!(after.registers.sp, before.registers.sp);
+    }
+
+    #[test]
+    fn step_returns_instruction_cycles() {
+        let program = [
+            0x00, // NOP
+            0x3E, 0x42, // LD A,0x42
+            0x21, 0x00, 0xC0, // LD HL,0xC000
+            0x77, // LD (HL),A
+            0xCD, 0x0C, 0x01, // CALL 0x010C
+            0x00, 0x00, // NOP, returned to
+            0xC9, // RET
+        ];
+        let mut console = console(&program, false);
+
+        let cycles: Vec<_> = (0..6).map(|_| console.step()).collect();
+        assert_eq!(cycles, [4, 8, 12, 8, 24, 16]);
+        assert_eq!(console.cpu_state().registers.pc, 0x10A);
+        assert_eq!(console.peek(0xC000), 0x42);
+    }
+
+    #[test]
+    fn custom_dmg_palette() {
+        let mut console = console(&[0x18, 0xFE], false);
+
+        // a tile of colours 0, 1, 2, 3, 0, 1, 2, 3 in the top left corner
+        console.write(0xFF40, 0x00);
+        for row in 0..8 {
+            console.write(0x8010 + row * 2, 0x55);
+            console.write(0x8011 + row * 2, 0x33);
+        }
+        console.write(0x9800, 1);
+        console.write(0xFF47, 0xE4);
+        console.write(0xFF40, 0x91);
+
+        let palette = [
+            [0xFF, 0x00, 0x00, 0xFF],
+            [0x00, 0xFF, 0x00, 0xFF],
+            [0x00, 0x00, 0xFF, 0xFF],
+            [0x10, 0x20, 0x30, 0xFF],
+        ];
+        console.set_dmg_palette(palette);
+        console.run_frame();
+
+        assert_eq!(console.framebuffer_rgba()[..16], palette.concat()[..]);
+    }
+
+    #[test]
+    fn peripherals_request_interrupts() {
+        let program = [
+            0xF3, // DI
+            0xAF, // XOR A
+            0xE0, 0x0F, // LDH (IF),A
+            0x3E, 0x05, // LD A,0x05
+            0xE0, 0x07, // LDH (TAC),A
+            0x3E, 0x08, // LD A,0x08
+            0xE0, 0x41, // LDH (STAT),A
+            0x3E, 0x81, // LD A,0x81
+            0xE0, 0x02, // LDH (SC),A
+            0x18, 0xFE, // JR -2
+        ];
+        let mut console = console(&program, false);
+        console.run_frame();
+
+        // VBlank, the HBlank STAT source, a timer overflow and a serial transfer, but no joypad
+        assert_eq!(console.peek(0xFF0F) & 0x1F, 0x0F);
+    }
+
+    #[test]
+    fn reset_returns_to_power_on() {
+        let program = [
+            0x3E, 0x42, // LD A,0x42
+            0xEA, 0x00, 0xC0, // LD (0xC000),A
+            0x47, // LD B,A
+            0x18, 0xFE, // JR -2
+        ];
+        let mut console = console(&program, false);
+        let fresh = console.cpu_state();
+
+        console.run_frame();
+        console.run_frame();
+        assert_eq!(console.peek(0xC000), 0x42);
+        assert_ne!(console.cpu_state().registers, fresh.registers);
+
+        console.reset();
+        assert_eq!(console.cpu_state().registers, fresh.registers);
+        assert_eq!(console.peek(0xC000), 0x00);
+        assert_eq!(console.peek(0xFF44), 0);
+    }
+
+    #[test]
+    fn reset_keeps_battery_ram() {
+        let program = [
+            0x3E, 0x0A, // LD A,0x0A
+            0xEA, 0x00, 0x00, // LD (0x0000),A
+            0xEA, 0x00, 0xA0, // LD (0xA000),A
+            0x18, 0xFE, // JR -2
+        ];
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x149] = 0x02; // 8KB
+
+        let mut console = Console::new();
+        console.load_rom_bytes(&rom).unwrap();
+        console.run_frame();
+
+        console.reset();
+        assert_eq!(console.dump_ram()[0], 0x0A);
+    }
+
+    #[test]
+    fn run_frame_stops_at_vblank() {
+        let mut console = console(&[0xF3, 0x18, 0xFE], false); // DI; JR -2
+
+        for _ in 0..5 {
+            console.write(0xFF0F, 0x00);
+
+            assert!(console.run_frame());
+            assert_eq!(console.peek(0xFF44), 144);
+            assert_eq!(console.peek(0xFF0F) & 0x01, 0x01);
+
+            // the next frame starts straight after the VBlank it stopped at
+            console.write(0xFF0F, 0x00);
+            console.step();
+            assert_eq!(console.peek(0xFF0F) & 0x01, 0x00);
+        }
+    }
+
+    #[test]
+    fn frames_keep_exact_time() {
+        let mut console = console(&[0xF3, 0x00, 0x18, 0xFD], false); // DI; NOP; JR -3
+        console.write(0xFF40, 0x00); // LCD off, so every frame is a fixed budget
+
+        // the cycle count before the most recent instruction
+        let started_at = Rc::new(Cell::new(0));
+        console.set_trace(Box::new({
+            let started_at = Rc::clone(&started_at);
+            move |line| started_at.set(line.cycles)
+        }));
+
+        for frames in 1..=100 {
+            assert!(!console.run_frame());
+
+            // the last instruction started inside the budget and is the longest one run, JR
+            let budget = frames * CPU_CYCLES_PER_FRAME;
+            assert!(started_at.get() < budget);
+            assert!(started_at.get() + 12 >= budget);
+        }
+    }
+
+    #[test]
+    fn run_until_serial_text_gives_up() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+
+        let mut console = console(&serial_output(b"Still running the tests"), false);
+        console.set_serial_callback({
+            let output = Rc::clone(&output);
+            move |byte| output.borrow_mut().push(byte)
+        });
+
+        let partial = console.run_until_serial_text("Passed", 1).unwrap_err();
+        assert!(!partial.is_empty() && partial.len() < 23, "{}", partial);
+
+        // each run collects its own output
+        let rest = console.run_until_serial_text("Passed", 10).unwrap_err();
+        assert_eq!(partial + &rest, "Still running the tests");
+
+        // and the frontend's callback is put back afterwards
+        assert!(output.borrow().is_empty());
+        console.write(0xFF01, b'!');
+        console.write(0xFF02, 0x81);
+        console.run_frame();
+        assert_eq!(&output.borrow()[..], b"!");
+    }
+
+    #[test]
+    fn read_write_and_peek() {
+        let mut console = console(&[0x18, 0xFE], false); // JR -2
+
+        console.write(0xC123, 0x42);
+        assert_eq!(console.read(0xC123), 0x42);
+        assert_eq!(console.read(0xE123), 0x42); // echo RAM
+
+        // neither runs the CPU
+        assert_eq!(console.cpu_state().registers.pc, 0x100);
+
+        // whilst the PPU draws VRAM is locked from reads, but not from peeks
+        console.write(0x8000, 0x99);
+        while console.read(0xFF41) & 0x03 != 3 {
+            console.step();
+        }
+        assert_eq!(console.read(0x8000), 0xFF);
+        assert_eq!(console.peek(0x8000), 0x99);
+
+        assert_eq!(Console::new().read(0xC123), 0xFF);
+    }
+
+    #[test]
+    fn jump_to_self_stalls() {
+        let mut spinning = console(&[0xF3, 0x00, 0x18, 0xFE], false); // DI; NOP; JR -2
+
+        spinning.step();
+        spinning.step();
+        assert!(!spinning.is_stalled());
+
+        spinning.step();
+        assert!(spinning.is_stalled());
+
+        // with interrupts enabled the loop can still be left
+        let mut waiting = console(&[0xFB, 0x18, 0xFE], false); // EI; JR -2
+        for _ in 0..3 {
+            waiting.step();
+        }
+        assert!(!waiting.is_stalled());
+    }
+
+    #[test]
+    fn run_frames_matches_run_frame() {
+        let mut batched = scrolling_console();
+        let mut stepped = scrolling_console();
+
+        let framebuffer = batched.run_frames(3);
+        for _ in 0..3 {
+            stepped.run_frame();
+        }
+
+        assert_eq!(framebuffer, stepped.framebuffer_rgba());
+        assert_eq!(batched.save_state(), stepped.save_state());
+    }
+
+    #[test]
+    fn tick_frame_returns_one_frame() {
+        let mut console = scrolling_console();
+        console.tick_frame();
+
+        for _ in 0..3 {
+            let output = console.tick_frame();
+            assert!(output.frame_ready);
+            assert_eq!(output.framebuffer.len(), 160 * 144 * 4);
+            assert_eq!(output.framebuffer, console.framebuffer_rgba());
+
+            // 70224 cycles at 44.1kHz is 738.35 samples
+            assert!((738..=739).contains(&output.audio_samples));
+        }
+    }
+
+    #[test]
+    fn frame_hash_is_stable() {
+        let hashes = || {
+            let mut console = scrolling_console();
+            let mut hashes = vec![];
+
+            for _ in 0..4 {
+                console.run_frame();
+                hashes.push(console.frame_hash());
+            }
+
+            // the DMG palette only affects the RGBA output
+            console.set_dmg_palette([[0xFF; 4]; 4]);
+            assert_eq!(console.frame_hash(), hashes[3]);
+
+            hashes
+        };
+
+        let first = hashes();
+        assert_eq!(first, hashes());
+
+        // every frame is scrolled differently
+        assert!(first.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn start_up_models() {
+        let start = |model| {
+            let mut console = Console::with_model(model);
+            console.load_rom_bytes(&[0; 0x8000]).unwrap();
+            (console.cpu_state().registers, console.peek(0xFF40))
+        };
+
+        let (registers, lcdc) = start(Model::Cgb);
+        assert_eq!((registers.a, registers.d, registers.e), (0x11, 0xFF, 0x56));
+        assert_eq!(lcdc, 0x91);
+
+        let (registers, lcdc) = start(Model::Dmg);
+        assert_eq!(
+            (registers.a, registers.c, registers.sp),
+            (0x01, 0x13, 0xFFFE)
+        );
+        assert_eq!(lcdc, 0x91);
+
+        // nothing has been set up, as if the boot ROM were about to run
+        let (registers, lcdc) = start(Model::Zeroed);
+        assert_eq!(registers, Registers::default());
+        assert_eq!(lcdc, 0x00);
+    }
+
+    #[test]
+    fn run_cycles_adds_up_to_a_frame() {
+        let program = [0xF3, 0x3C, 0x18, 0xFD]; // DI; INC A; JR -3
+        let mut framed = console(&program, false);
+        let mut budgeted = console(&program, false);
+
+        // with the LCD off a frame is a fixed budget rather than running to VBlank
+        framed.write(0xFF40, 0x00);
+        budgeted.write(0xFF40, 0x00);
+
+        framed.run_frame();
+        let mut cycles: usize = (0..70).map(|_| budgeted.run_cycles(1000)).sum();
+        cycles += budgeted.run_cycles(224);
+
+        assert_eq!(framed.cpu_state().registers, budgeted.cpu_state().registers);
+        assert!((CPU_CYCLES_PER_FRAME..CPU_CYCLES_PER_FRAME + 12).contains(&cycles));
+    }
+
+    #[test]
+    fn hblank_callback_every_visible_line() {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+
+        let mut console = console(&[0x18, 0xFE], false); // JR -2
+        console.run_frame();
+
+        console.set_hblank_callback(Box::new({
+            let lines = Rc::clone(&lines);
+            move |ly| lines.borrow_mut().push(ly)
+        }));
+
+        console.run_frame();
+        assert_eq!(*lines.borrow(), (0..144).collect::<Vec<u8>>());
+
+        console.clear_hblank_callback();
+        console.run_frame();
+        assert_eq!(lines.borrow().len(), 144);
+    }
+
+    #[test]
+    fn export_frame_as_ppm() {
+        let mut console = scrolling_console();
+        console.run_frame();
+
+        let path = std::env::temp_dir().join(format!("frame-{}.ppm", std::process::id()));
+        console.export_ppm(&path).unwrap();
+        let ppm = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header = b"P6\n160 144\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(ppm.len(), header.len() + 160 * 144 * 3);
+
+        // the RGB of each RGBA pixel
+        let rgb: Vec<u8> = console
+            .framebuffer_rgba()
+            .chunks(4)
+            .flat_map(|pixel| pixel[..3].to_vec())
+            .collect();
+        assert_eq!(&ppm[header.len()..], &rgb[..]);
+    }
+
+    #[test]
+    fn replayed_input_gives_the_same_frames() {
+        // scrolls the background vertically by the state of the action buttons
+        let program = [
+            0x3E, 0x10, // LD A,0x10
+            0xE0, 0x00, // LDH (P1),A
+            0xF0, 0x00, // LDH A,(P1)
+            0xE0, 0x42, // LDH (SCY),A
+            0x18, 0xF6, // JR -10
+        ];
+
+        let mut recorded = console_with_background(&program);
+        recorded.start_recording();
+
+        let mut hashes = vec![];
+        for frame in 0..6 {
+            recorded.set_button(Button::A, frame == 1 || frame == 2);
+            recorded.set_button(Button::Start, frame == 4);
+            recorded.run_frame();
+            hashes.push(recorded.frame_hash());
+        }
+
+        let log = recorded.stop_recording();
+        assert_eq!(log.len(), 6);
+
+        // the input changed what was drawn
+        assert_ne!(hashes[0], hashes[2]);
+        assert_ne!(hashes[2], hashes[4]);
+
+        let mut replayed = console_with_background(&program);
+        replayed.play_input(log);
+
+        for hash in hashes {
+            replayed.run_frame();
+            assert_eq!(replayed.frame_hash(), hash);
+        }
+    }
+
+    #[test]
+    fn ppu_mode_sequence() {
+        let mut console = console(&[], false); // NOPs all the way
+
+        // restarting the LCD begins line 0 at its first dot
+        console.write(0xFF40, 0x00);
+        console.write(0xFF40, 0x91);
+        assert_eq!(console.ppu_mode(), Mode::OAMRead);
+        assert_eq!((console.current_line(), console.dot_in_line()), (0, 0));
+
+        let mut modes = vec![console.ppu_mode()];
+        while console.current_line() == 0 {
+            console.step();
+
+            if modes.last() != Some(&console.ppu_mode()) {
+                modes.push(console.ppu_mode());
             }
         }
+
+        assert_eq!(
+            modes,
+            [Mode::OAMRead, Mode::VRAMRead, Mode::HBlank, Mode::OAMRead]
+        );
+        assert_eq!((console.current_line(), console.dot_in_line()), (1, 0));
+    }
+
+    #[test]
+    fn palette_presets_differ() {
+        let mut console = console(&[0x18, 0xFE], false); // JR -2
+        console.run_frame();
+
+        let presets = [
+            PalettePreset::Dmg,
+            PalettePreset::Grayscale,
+            PalettePreset::HighContrast,
+            PalettePreset::Pocket,
+        ];
+
+        // the blank screen is drawn entirely in colour 0
+        let colour_0: Vec<Vec<u8>> = presets
+            .iter()
+            .map(|&preset| {
+                console.set_palette_preset(preset);
+                console.framebuffer_rgba()[..4].to_vec()
+            })
+            .collect();
+
+        for (i, colour) in colour_0.iter().enumerate() {
+            assert_eq!(colour[..], presets[i].colours()[0]);
+            assert!(!colour_0[..i].contains(colour), "{:?}", presets[i]);
+        }
     }
 }
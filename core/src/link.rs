@@ -0,0 +1,153 @@
+use crate::{Console, CPU_CYCLES_PER_FRAME};
+
+// connects two consoles, each transfer clocked by one side exchanges SB with the other
+pub struct LinkCable {
+    cycles: [usize; 2],
+    // the byte due to the other side once this side's transfer completes
+    pending: [Option<u8>; 2],
+}
+
+impl LinkCable {
+    pub fn new() -> Self {
+        LinkCable {
+            cycles: [0; 2],
+            pending: [None; 2],
+        }
+    }
+}
+
+impl Default for LinkCable {
+    fn default() -> Self {
+        LinkCable::new()
+    }
+}
+
+impl LinkCable {
+    // steps whichever console is behind by one instruction, returning the cycles it took,
+    // so neither gets more than an instruction ahead of the other
+    pub fn step(&mut self, a: &mut Console, b: &mut Console) -> usize {
+        let cycles = if self.cycles[0] <= self.cycles[1] {
+            let cycles = a.step();
+            self.cycles[0] += cycles;
+            cycles
+        } else {
+            let cycles = b.step();
+            self.cycles[1] += cycles;
+            cycles
+        };
+
+        self.connect(0, a, b);
+        self.connect(1, b, a);
+
+        cycles
+    }
+
+    // runs both consoles until a reaches its next VBlank, returning true if it drew a frame
+    pub fn run_frame(&mut self, a: &mut Console, b: &mut Console) -> bool {
+        if a.cartridge.is_none() || b.cartridge.is_none() {
+            return false;
+        }
+
+        let mut elapsed_cycles = 0;
+
+        while elapsed_cycles < CPU_CYCLES_PER_FRAME {
+            let a_cycles = self.cycles[0];
+            self.step(a, b);
+
+            // count only a's time, measured from before the step
+            elapsed_cycles += self.cycles[0].saturating_sub(a_cycles);
+
+            // b's frames aren't waited for
+            b.video.take_frame_ready();
+
+            if a.video.take_frame_ready() {
+                return !a.video.frame_skipped();
+            }
+        }
+
+        false
+    }
+
+    fn connect(&mut self, side: usize, master: &mut Console, slave: &mut Console) {
+        if master.serial.awaiting_peer() {
+            if slave.serial.awaiting_clock() {
+                master.serial.receive_byte(slave.serial.sb);
+                self.pending[side] = Some(master.serial.sb);
+            } else {
+                // the peer isn't ready, so the line floats high
+                master.serial.receive_byte(0xFF);
+            }
+        }
+
+        if master.serial.clocking() {
+            return;
+        }
+
+        // the slave's transfer completes alongside the master's
+        if let Some(value) = self.pending[side].take() {
+            slave.serial.receive_byte(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::console;
+
+    // puts value in SB and starts a transfer with the given SC, the master waits first so the
+    // other side is ready
+    fn transfer(value: u8, sc: u8) -> Console {
+        let mut program = vec![];
+
+        if sc & 0x01 != 0 {
+            program.extend_from_slice(&[0x00; 8]); // NOP
+        }
+
+        program.extend_from_slice(&[
+            0x3E, value, // LD A,value
+            0xE0, 0x01, // LDH (SB),A
+            0x3E, sc, // LD A,sc
+            0xE0, 0x02, // LDH (SC),A
+            0x18, 0xFE, // JR -2
+        ]);
+
+        console(&program, false)
+    }
+
+    fn exchange(a: &mut Console, b: &mut Console) {
+        let mut cable = LinkCable::new();
+
+        // a whole byte at 8192Hz, with time to spare
+        while cable.cycles[0] < 8192 {
+            cable.step(a, b);
+        }
+    }
+
+    #[test]
+    fn exchanges_a_byte_each_way() {
+        let mut master = transfer(0x42, 0x81);
+        let mut slave = transfer(0x99, 0x80);
+
+        exchange(&mut master, &mut slave);
+
+        assert_eq!(master.peek(0xFF01), 0x99);
+        assert_eq!(slave.peek(0xFF01), 0x42);
+
+        for console in &mut [master, slave] {
+            assert_eq!(console.peek(0xFF02) & 0x80, 0);
+            assert_ne!(console.peek(0xFF0F) & 0x08, 0);
+        }
+    }
+
+    #[test]
+    fn either_side_can_clock() {
+        let mut slave = transfer(0x24, 0x80);
+        let mut master = transfer(0x7E, 0x81);
+
+        exchange(&mut slave, &mut master);
+
+        assert_eq!(slave.peek(0xFF01), 0x7E);
+        assert_eq!(master.peek(0xFF01), 0x24);
+    }
+}
@@ -1,8 +1,13 @@
-use std::{ops::Index, path::Path};
+use alloc::{string::String, vec::Vec};
+use core::ops::Index;
+#[cfg(feature = "std")]
+use std::{io, path::Path};
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum CartridgeType {
     ROMOnly,
     MBC1,
+    MBC2,
 }
 
 pub struct ROM(Vec<u8>);
@@ -10,48 +15,244 @@ pub struct ROM(Vec<u8>);
 impl Index<usize> for ROM {
     type Output = u8;
 
+    // reads past the end of the file see the open bus
     fn index(&self, index: usize) -> &Self::Output {
-        &self.0[index]
+        self.0.get(index).unwrap_or(&0xFF)
     }
 }
 
 impl ROM {
-    pub fn from_file<P>(path: P) -> Result<Self, std::io::Error>
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        ROM(bytes)
+    }
+}
+
+#[cfg(feature = "std")]
+impl ROM {
+    pub fn from_file<P>(path: P) -> Result<Self, io::Error>
     where
         P: AsRef<Path>,
     {
         let bytes = std::fs::read(path)?;
-        Ok(ROM(bytes))
+        Ok(ROM::from_bytes(bytes))
+    }
+
+    // as from_file, but rejects ROMs whose header or global checksum doesn't match
+    pub fn from_file_checked<P>(path: P) -> Result<Self, io::Error>
+    where
+        P: AsRef<Path>,
+    {
+        let rom = ROM::from_file(path)?;
+
+        if rom.0.len() < 0x150 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too small to contain a header",
+            ));
+        }
+
+        if !rom.header_checksum_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid header checksum",
+            ));
+        }
+
+        if !rom.global_checksum_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid global checksum",
+            ));
+        }
+
+        Ok(rom)
     }
 }
 
 impl ROM {
     pub fn title(&self) -> String {
-        let title = &self.0[0x134..=0x143];
+        let title = self.0.get(0x134..=0x143).unwrap_or(&[]);
         let title = if let Some(i) = title.iter().position(|&x| x == 0) {
             &title[0..i]
         } else {
             title
         };
 
-        String::from_utf8(title.to_vec()).unwrap()
+        String::from_utf8_lossy(title).into_owned()
     }
 
-    pub fn cartridge_type(&self) -> CartridgeType {
-        match self.0[0x147] {
-            0x00 => CartridgeType::ROMOnly,
-            0x01 => CartridgeType::MBC1,
-            _ => unimplemented!(),
+    // None for cartridge types which aren't supported yet
+    pub fn cartridge_type(&self) -> Option<CartridgeType> {
+        match self[0x147] {
+            0x00 => Some(CartridgeType::ROMOnly),
+            0x01..=0x03 => Some(CartridgeType::MBC1),
+            0x05..=0x06 => Some(CartridgeType::MBC2),
+            _ => None,
         }
     }
 
-    pub fn ram_size(&self) -> usize {
-        match self.0[0x149] {
-            0x00 => 0,
-            0x01 => 2048,
-            0x02 => 8192,
-            0x03 => 32768,
-            _ => unreachable!(),
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self[0x147],
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+        )
+    }
+
+    // MBC3 carts with a real time clock
+    pub fn has_rtc(&self) -> bool {
+        matches!(self[0x147], 0x0F | 0x10)
+    }
+
+    // the number of 16KB banks actually present in the file
+    pub fn banks(&self) -> usize {
+        self.0.len().div_ceil(0x4000).max(1)
+    }
+
+    pub fn cgb_flag(&self) -> u8 {
+        self[0x143]
+    }
+
+    pub fn sgb_flag(&self) -> u8 {
+        self[0x146]
+    }
+
+    pub fn licensee_code(&self) -> u8 {
+        self[0x14B]
+    }
+
+    // 32KB shifted left by the size code, 0x00-0x08 are the only codes in use
+    pub fn rom_size(&self) -> Option<usize> {
+        match self[0x148] {
+            code @ 0x00..=0x08 => Some(0x8000 << code),
+            _ => None,
         }
     }
+
+    // the boot ROM refuses to start a cartridge when this doesn't match
+    pub fn header_checksum_valid(&self) -> bool {
+        let checksum = (0x134..=0x14C)
+            .map(|address| self[address])
+            .fold(0u8, |x, byte| x.wrapping_sub(byte).wrapping_sub(1));
+
+        checksum == self[0x14D]
+    }
+
+    // the sum of every byte except the checksum itself, which hardware never checks
+    pub fn global_checksum_valid(&self) -> bool {
+        let checksum = self
+            .0
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 0x14E && *i != 0x14F)
+            .fold(0u16, |sum, (_, byte)| sum.wrapping_add(u16::from(*byte)));
+
+        checksum == u16::from_be_bytes([self[0x14E], self[0x14F]])
+    }
+
+    pub fn ram_size(&self) -> Option<usize> {
+        match self[0x149] {
+            0x00 => Some(0),
+            0x01 => Some(2048),
+            0x02 => Some(8192),
+            0x03 => Some(32768),
+            0x04 => Some(131_072),
+            0x05 => Some(65536),
+            _ => None,
+        }
+    }
+}
+
+pub struct BootROM {
+    bytes: Vec<u8>,
+    pub mapped: bool,
+}
+
+impl BootROM {
+    pub fn new(bytes: &[u8]) -> Self {
+        BootROM {
+            bytes: bytes.to_vec(),
+            mapped: true,
+        }
+    }
+}
+
+impl BootROM {
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match self.bytes.get(usize::from(address)) {
+            Some(value) => *value,
+            None => 0xFF,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with_size_code(code: u8) -> ROM {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x148] = code;
+        ROM::from_bytes(bytes)
+    }
+
+    #[test]
+    fn rom_size_from_header() {
+        assert_eq!(rom_with_size_code(0x00).rom_size(), Some(0x8000));
+        assert_eq!(rom_with_size_code(0x05).rom_size(), Some(0x10_0000));
+        assert_eq!(rom_with_size_code(0x08).rom_size(), Some(0x80_0000));
+    }
+
+    #[test]
+    fn rom_size_unknown_code() {
+        for &code in &[0x09, 0x50, 0xFF] {
+            assert_eq!(rom_with_size_code(code).rom_size(), None);
+        }
+    }
+
+    fn rom_with_header(title: &[u8], checksum: u8) -> ROM {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x134..0x134 + title.len()].copy_from_slice(title);
+        bytes[0x14D] = checksum;
+        ROM::from_bytes(bytes)
+    }
+
+    #[test]
+    fn header_checksum() {
+        // x = x - byte - 1 over 0x134-0x14C, so 25 blank bytes give -25
+        assert!(rom_with_header(b"", 0xE7).header_checksum_valid());
+        assert!(!rom_with_header(b"", 0xE6).header_checksum_valid());
+
+        // 0 - 0x140 - 25
+        assert!(rom_with_header(b"TEST", 0xA7).header_checksum_valid());
+        assert!(!rom_with_header(b"TEST", 0xE7).header_checksum_valid());
+    }
+
+    #[test]
+    fn global_checksum() {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x100] = 0x12;
+        bytes[0x7FFF] = 0xF0;
+        bytes[0x14E] = 0x01;
+        bytes[0x14F] = 0x02;
+        assert!(ROM::from_bytes(bytes.clone()).global_checksum_valid());
+
+        bytes[0x200] = 0x01;
+        assert!(!ROM::from_bytes(bytes).global_checksum_valid());
+    }
+
+    #[test]
+    fn title_from_embedded_bytes() {
+        const ROM_BYTES: &[u8] = &[0; 0x150];
+
+        let mut bytes = ROM_BYTES.to_vec();
+        bytes[0x134..0x13A].copy_from_slice(b"TETRIS");
+        assert_eq!(ROM::from_bytes(bytes.clone()).title(), "TETRIS");
+
+        // all 16 bytes are used when there's no terminator
+        bytes[0x134..0x144].copy_from_slice(b"SIXTEEN BYTES OK");
+        assert_eq!(ROM::from_bytes(bytes).title(), "SIXTEEN BYTES OK");
+
+        // too short to have a header
+        assert_eq!(ROM::from_bytes(vec![0x41; 0x100]).title(), "");
+    }
 }
@@ -1,12 +1,22 @@
-// TODO: Shifting bits in/out during transfer
 use crate::interrupts::Interrupt;
-use std::io;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use alloc::boxed::Box;
+use bit_field::BitField;
+
+// the internal clock runs at 8192Hz, or 262144Hz on CGB with the fast clock selected
+const CYCLES_PER_BIT: usize = 512;
+const FAST_CYCLES_PER_BIT: usize = 16;
 
 pub struct Serial {
     pub sb: u8,
     pub sc: u8,
 
+    incoming: Option<u8>,
+    outgoing: u8,
+    bits: usize,
     transfer_cycles: usize,
+    cgb_mode: bool,
+    callback: Box<dyn FnMut(u8)>,
 }
 
 impl Serial {
@@ -15,33 +25,235 @@ impl Serial {
             sb: 0,
             sc: 0,
 
+            incoming: None,
+            outgoing: 0,
+            bits: 0,
             transfer_cycles: 0,
+            cgb_mode: false,
+            callback: Box::new(|_| {}),
         }
     }
 }
 
 impl Serial {
-    pub fn step(&mut self, cycles: usize) -> Vec<Interrupt> {
-        let mut interrupts = vec![];
+    // the fast clock in bit 1 of SC only exists on CGB
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
 
-        if self.sc == 0x81 {
-            self.transfer_cycles += cycles;
+    pub fn set_callback(&mut self, callback: Box<dyn FnMut(u8)>) {
+        self.callback = callback;
+    }
+
+    pub fn replace_callback(&mut self, callback: Box<dyn FnMut(u8)>) -> Box<dyn FnMut(u8)> {
+        core::mem::replace(&mut self.callback, callback)
+    }
+
+    // returns to the power-on state, keeping the callback
+    pub fn reset(&mut self) {
+        self.sb = 0;
+        self.sc = 0;
+
+        self.incoming = None;
+        self.outgoing = 0;
+        self.bits = 0;
+        self.transfer_cycles = 0;
+    }
 
-            if self.transfer_cycles >= 8 {
-                print!("{}", self.sb as char);
+    pub fn write_control(&mut self, value: u8) {
+        // setting the start bit begins a new transfer
+        if value.get_bit(7) && !self.sc.get_bit(7) {
+            self.outgoing = 0;
+            self.bits = 0;
+            self.transfer_cycles = 0;
+        }
 
-                use io::Write;
-                io::stdout().flush().unwrap();
+        self.sc = value;
+    }
 
-                self.sb = 0xFF;
-                self.sc = 0x01;
+    // this side is clocking a transfer, and hasn't been given the peer's byte yet
+    pub fn awaiting_peer(&self) -> bool {
+        self.sc.get_bit(7) && self.sc.get_bit(0) && self.incoming.is_none() && self.bits == 0
+    }
+
+    // true until a transfer driven by the internal clock completes
+    pub fn clocking(&self) -> bool {
+        self.sc.get_bit(7) && self.sc.get_bit(0)
+    }
+
+    // this side has started a transfer, and is waiting for the peer to clock it
+    pub fn awaiting_clock(&self) -> bool {
+        self.sc.get_bit(7) && !self.sc.get_bit(0)
+    }
+
+    // latches the byte sent by the peer, it is shifted in during the next transfer
+    pub fn receive_byte(&mut self, value: u8) {
+        self.incoming = Some(value);
+    }
 
-                self.transfer_cycles = 0;
+    pub fn step(&mut self, cycles: usize) -> u8 {
+        let mut interrupts = 0;
 
-                interrupts.push(Interrupt::Serial);
+        if !self.sc.get_bit(7) {
+            return interrupts;
+        }
+
+        if self.sc.get_bit(0) {
+            // internal clock, shift one bit every period
+            let period = if self.cgb_mode && self.sc.get_bit(1) {
+                FAST_CYCLES_PER_BIT
+            } else {
+                CYCLES_PER_BIT
+            };
+
+            self.transfer_cycles += cycles;
+
+            while self.transfer_cycles >= period && self.bits < 8 {
+                self.transfer_cycles -= period;
+                self.shift_bit();
             }
+        } else if self.incoming.is_some() {
+            // external clock, the peer clocks the whole byte
+            while self.bits < 8 {
+                self.shift_bit();
+            }
+        }
+
+        if self.bits == 8 {
+            (self.callback)(self.outgoing);
+
+            self.sc.set_bit(7, false);
+
+            self.incoming = None;
+            self.bits = 0;
+            self.transfer_cycles = 0;
+
+            interrupts |= u8::from(Interrupt::Serial);
+        }
+
+        interrupts
+    }
+
+    fn shift_bit(&mut self) {
+        // with nothing connected the line floats high
+        let incoming = self.incoming.unwrap_or(0xFF);
+
+        self.outgoing = (self.outgoing << 1) | u8::from(self.sb.get_bit(7));
+        self.sb = (self.sb << 1) | u8::from(incoming.get_bit(7 - self.bits));
+
+        self.bits += 1;
+    }
+}
+
+impl Snapshot for Serial {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.sb);
+        state.write_u8(self.sc);
+        state.write_bool(self.incoming.is_some());
+        state.write_u8(self.incoming.unwrap_or(0));
+        state.write_u8(self.outgoing);
+        state.write_usize(self.bits);
+        state.write_usize(self.transfer_cycles);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.sb = state.read_u8()?;
+        self.sc = state.read_u8()?;
+
+        let has_incoming = state.read_bool()?;
+        let incoming = state.read_u8()?;
+        self.incoming = if has_incoming { Some(incoming) } else { None };
+
+        self.outgoing = state.read_u8()?;
+        self.bits = state.read_usize()?;
+        self.transfer_cycles = state.read_usize()?;
+
+        if self.bits > 8 {
+            return Err(StateError::InvalidValue);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn step_machine_cycles(serial: &mut Serial, cycles: usize) -> u8 {
+        let mut interrupts = 0;
+
+        for _ in 0..cycles / 4 {
+            interrupts |= serial.step(4);
         }
 
         interrupts
     }
+
+    #[test]
+    fn internal_clock_transfer_exchanges_bytes() {
+        let sent = Rc::new(Cell::new(None));
+
+        let mut serial = Serial::new();
+        serial.set_callback(Box::new({
+            let sent = sent.clone();
+            move |byte| sent.set(Some(byte))
+        }));
+
+        serial.sb = 0xA5;
+        serial.receive_byte(0x3C);
+        serial.write_control(0x81);
+
+        let interrupts = step_machine_cycles(&mut serial, 8 * CYCLES_PER_BIT);
+
+        assert_eq!(interrupts, u8::from(Interrupt::Serial));
+        assert_eq!(serial.sb, 0x3C);
+        assert_eq!(sent.get(), Some(0xA5));
+        assert!(!serial.sc.get_bit(7));
+    }
+
+    #[test]
+    fn external_clock_waits_for_peer() {
+        let mut serial = Serial::new();
+        serial.sb = 0x11;
+        serial.write_control(0x80);
+
+        assert_eq!(step_machine_cycles(&mut serial, 8192), 0);
+        assert!(serial.awaiting_clock());
+
+        serial.receive_byte(0x5A);
+        assert_eq!(serial.step(4), u8::from(Interrupt::Serial));
+        assert_eq!(serial.sb, 0x5A);
+    }
+
+    #[test]
+    fn transfer_takes_4096_cycles() {
+        let mut serial = Serial::new();
+        serial.write_control(0x81);
+
+        assert_eq!(step_machine_cycles(&mut serial, 4092), 0);
+        assert!(serial.sc.get_bit(7));
+
+        assert_eq!(serial.step(4), u8::from(Interrupt::Serial));
+        assert!(!serial.sc.get_bit(7));
+    }
+
+    #[test]
+    fn cgb_fast_clock() {
+        let mut serial = Serial::new();
+        serial.set_cgb_mode(true);
+        serial.write_control(0x83);
+
+        assert_eq!(step_machine_cycles(&mut serial, 124), 0);
+        assert_eq!(serial.step(4), u8::from(Interrupt::Serial));
+
+        // the fast clock bit is ignored on DMG
+        let mut serial = Serial::new();
+        serial.write_control(0x83);
+
+        assert_eq!(step_machine_cycles(&mut serial, 128), 0);
+        assert!(serial.sc.get_bit(7));
+    }
 }
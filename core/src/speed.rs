@@ -0,0 +1,106 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use bit_field::BitField;
+
+// the CGB speed switch, KEY1
+pub struct Speed {
+    double_speed: bool,
+    armed: bool,
+    cgb_mode: bool,
+}
+
+impl Speed {
+    pub fn new() -> Self {
+        Speed {
+            double_speed: false,
+            armed: false,
+            cgb_mode: false,
+        }
+    }
+}
+
+impl Speed {
+    // KEY1 doesn't exist on DMG, it reads 0xFF and the speed never changes
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    pub fn read_byte(&self) -> u8 {
+        if !self.cgb_mode {
+            return 0xFF;
+        }
+
+        let mut value = 0x7E;
+
+        value.set_bit(7, self.double_speed);
+        value.set_bit(0, self.armed);
+
+        value
+    }
+
+    // only the prepare bit can be written, the switch itself happens on STOP
+    pub fn write_byte(&mut self, value: u8) {
+        if !self.cgb_mode {
+            return;
+        }
+
+        self.armed = value.get_bit(0);
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // returns true if a switch was armed and has now been performed
+    pub fn switch(&mut self) -> bool {
+        if !self.armed {
+            return false;
+        }
+
+        self.double_speed = !self.double_speed;
+        self.armed = false;
+
+        true
+    }
+}
+
+impl Snapshot for Speed {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bool(self.double_speed);
+        state.write_bool(self.armed);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.double_speed = state.read_bool()?;
+        self.armed = state.read_bool()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dmg_ignores_key1() {
+        let mut speed = Speed::new();
+        speed.write_byte(0x01);
+
+        assert_eq!(speed.read_byte(), 0xFF);
+        assert!(!speed.switch());
+        assert!(!speed.double_speed());
+    }
+
+    #[test]
+    fn cgb_switches_when_armed() {
+        let mut speed = Speed::new();
+        speed.set_cgb_mode(true);
+        assert!(!speed.switch());
+
+        speed.write_byte(0x01);
+        assert_eq!(speed.read_byte(), 0x7F);
+        assert!(speed.switch());
+        assert!(speed.double_speed());
+        assert_eq!(speed.read_byte(), 0xFE);
+    }
+}
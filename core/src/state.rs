@@ -0,0 +1,141 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+const MAGIC: &[u8; 4] = b"GBST";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum StateError {
+    InvalidHeader,
+    UnsupportedVersion(u8),
+    CartridgeMismatch,
+    UnexpectedEnd,
+    InvalidValue,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::InvalidHeader => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::CartridgeMismatch => {
+                write!(f, "save state was made with a different cartridge")
+            }
+            StateError::UnexpectedEnd => write!(f, "save state is truncated"),
+            StateError::InvalidValue => write!(f, "save state contains an invalid value"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StateError {}
+
+pub trait Snapshot {
+    fn save(&self, state: &mut StateWriter);
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+
+        StateWriter { bytes }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl StateWriter {
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_usize(&mut self, value: usize) {
+        self.write_bytes(&(value as u64).to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Result<Self, StateError> {
+        if bytes.len() < MAGIC.len() + 1 || &bytes[0..MAGIC.len()] != MAGIC {
+            return Err(StateError::InvalidHeader);
+        }
+
+        let version = bytes[MAGIC.len()];
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        Ok(StateReader {
+            bytes: &bytes[MAGIC.len() + 1..],
+        })
+    }
+}
+
+impl<'a> StateReader<'a> {
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        let mut value = [0; 1];
+        self.read_bytes(&mut value)?;
+
+        Ok(value[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidValue),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let mut value = [0; 2];
+        self.read_bytes(&mut value)?;
+
+        Ok(u16::from_le_bytes(value))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, StateError> {
+        let mut value = [0; 8];
+        self.read_bytes(&mut value)?;
+
+        Ok(u64::from_le_bytes(value) as usize)
+    }
+
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), StateError> {
+        if self.bytes.len() < buf.len() {
+            return Err(StateError::UnexpectedEnd);
+        }
+
+        let (bytes, rest) = self.bytes.split_at(buf.len());
+        buf.copy_from_slice(bytes);
+        self.bytes = rest;
+
+        Ok(())
+    }
+}
@@ -1,94 +1,288 @@
-//use crate::bus::{Interrupt, InterruptHandler};
-
 use crate::interrupts::Interrupt;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 use bit_field::BitField;
 
-pub struct Timer {
+// the timer's registers along with its hidden state
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimerState {
+    pub counter: u16,
     pub div: u8,
     pub tima: u8,
     pub tma: u8,
     pub tac: u8,
+    pub reload_pending: bool,
+}
+
+pub struct Timer {
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
 
-    divider_cycles: usize,
-    timer_cycles: usize,
+    // the 16-bit system counter, DIV is its upper byte
+    counter: u16,
+    reload_delay: Option<usize>,
 }
 
 impl Timer {
     pub fn new() -> Self {
         Timer {
-            div: 0,
             tima: 0,
             tma: 0,
             tac: 0,
 
-            divider_cycles: 0,
-            timer_cycles: 0,
+            counter: 0,
+            reload_delay: None,
         }
     }
 }
 
 impl Timer {
-    pub fn step(&mut self, cycles: usize) -> Vec<Interrupt> {
-        let mut interrupts = vec![];
+    pub fn div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
 
-        self.step_divider(cycles);
+    // any write to DIV clears the whole system counter
+    pub fn reset_div(&mut self) {
+        let signal = self.timer_signal();
+        self.counter = 0;
 
-        if self.timer_enabled() {
-            let overflow = self.step_timer(cycles);
+        // clearing the counter drops the selected bit, which counts as a falling edge
+        if signal {
+            self.increment_tima();
+        }
+    }
 
-            if overflow {
-                interrupts.push(Interrupt::Timer);
-            }
+    pub fn write_tac(&mut self, value: u8) {
+        let signal = self.timer_signal();
+        self.tac = value;
+
+        // disabling the timer or switching to a clear bit is also seen as a falling edge
+        if signal && !self.timer_signal() {
+            self.increment_tima();
         }
+    }
 
-        interrupts
+    pub fn state(&self) -> TimerState {
+        TimerState {
+            counter: self.counter,
+            div: self.div(),
+            tima: self.tima,
+            tma: self.tma,
+            tac: self.tac,
+            reload_pending: self.reload_delay.is_some(),
+        }
     }
-}
 
-impl Timer {
-    fn step_divider(&mut self, cycles: usize) {
-        self.divider_cycles += cycles;
+    pub fn step(&mut self, cycles: usize) -> u8 {
+        let mut interrupts = 0;
+
+        let overflow = self.step_timer(cycles);
 
-        while self.divider_cycles >= 256 {
-            // step div at 16384Hz, the CPU clock rate is 4194304Hz, so div is steps every 256 cycles
-            self.div = self.div.wrapping_add(1);
-            self.divider_cycles -= 256;
+        if overflow {
+            interrupts |= u8::from(Interrupt::Timer);
         }
+
+        interrupts
+    }
+
+    pub fn write_tima(&mut self, value: u8) {
+        // TIMA is about to be reloaded from TMA, which wins over the write
+        if self.reload_delay.is_some() {
+            return;
+        }
+
+        self.tima = value;
     }
+}
 
+impl Timer {
     fn timer_enabled(&self) -> bool {
         self.tac.get_bit(2)
     }
 
-    fn get_freq(&self) -> usize {
-        match self.tac.get_bits(0..1) {
-            0b00 => 1024, // 4096Hz
-            0b01 => 16,   // 262144Hz
-            0b10 => 64,   // 65536Hz
-            0b11 => 256,  // 16384Hz
+    // TIMA is incremented on the falling edge of a bit of the system counter selected by TAC,
+    // ANDed with the enable bit
+    fn timer_signal(&self) -> bool {
+        let bit = match self.tac.get_bits(0..2) {
+            0b00 => 9, // 4096Hz
+            0b01 => 3, // 262144Hz
+            0b10 => 5, // 65536Hz
+            0b11 => 7, // 16384Hz
             _ => unreachable!(),
+        };
+
+        self.timer_enabled() && self.counter.get_bit(bit)
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+
+        self.tima = tima;
+
+        if overflow {
+            self.reload_delay = Some(4);
         }
     }
 
+    // returns true when TIMA is reloaded after overflowing
     fn step_timer(&mut self, cycles: usize) -> bool {
         let mut has_overflown = false;
+        let mut cycles = cycles;
 
-        // increment tima at a rate of cycles / freq
-        self.timer_cycles += cycles;
+        // step a machine cycle at a time so the reload lands on the right cycle
+        while cycles > 0 {
+            let elapsed = cycles.min(4);
+            cycles -= elapsed;
 
-        while self.timer_cycles >= self.get_freq() {
-            let (tima, overflow) = self.tima.overflowing_add(1);
-
-            if overflow {
-                self.tima = self.tma;
-                has_overflown = true;
-            } else {
-                self.tima = tima;
+            // after overflowing TIMA reads 0 for a machine cycle before TMA is loaded
+            if let Some(delay) = self.reload_delay {
+                if delay <= elapsed {
+                    self.tima = self.tma;
+                    self.reload_delay = None;
+                    has_overflown = true;
+                } else {
+                    self.reload_delay = Some(delay - elapsed);
+                }
             }
 
-            self.timer_cycles -= self.get_freq();
+            // the system counter ticks every cycle, so DIV steps at 16384Hz
+            let signal = self.timer_signal();
+            self.counter = self.counter.wrapping_add(elapsed as u16);
+
+            if signal && !self.timer_signal() {
+                self.increment_tima();
+            }
         }
 
         has_overflown
     }
 }
+
+impl Snapshot for Timer {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_u8(self.tima);
+        state.write_u8(self.tma);
+        state.write_u8(self.tac);
+        state.write_bool(self.reload_delay.is_some());
+        state.write_usize(self.reload_delay.unwrap_or(0));
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.tima = state.read_u8()?;
+        self.tma = state.read_u8()?;
+        self.tac = state.read_u8()?;
+
+        let reload_pending = state.read_bool()?;
+        let reload_delay = state.read_usize()?;
+        self.reload_delay = if reload_pending {
+            Some(reload_delay)
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step_machine_cycles(timer: &mut Timer, cycles: usize) -> u8 {
+        let mut interrupts = 0;
+
+        for _ in 0..cycles / 4 {
+            interrupts |= timer.step(4);
+        }
+
+        interrupts
+    }
+
+    #[test]
+    fn tac_intervals() {
+        for &(tac, interval) in &[(0x04, 1024), (0x05, 16), (0x06, 64), (0x07, 256)] {
+            let mut timer = Timer::new();
+            timer.write_tac(tac);
+
+            step_machine_cycles(&mut timer, interval - 4);
+            assert_eq!(timer.tima, 0, "TAC {:02X}", tac);
+
+            step_machine_cycles(&mut timer, 4);
+            assert_eq!(timer.tima, 1, "TAC {:02X}", tac);
+
+            step_machine_cycles(&mut timer, interval * 9);
+            assert_eq!(timer.tima, 10, "TAC {:02X}", tac);
+        }
+    }
+
+    #[test]
+    fn disabled_timer_doesnt_count() {
+        let mut timer = Timer::new();
+        timer.write_tac(0x01);
+
+        step_machine_cycles(&mut timer, 4096);
+        assert_eq!(timer.tima, 0);
+    }
+
+    #[test]
+    fn div_is_upper_byte_of_counter() {
+        let mut timer = Timer::new();
+        step_machine_cycles(&mut timer, 0x1234);
+
+        let state = timer.state();
+        assert_eq!(state.counter, 0x1234);
+        assert_eq!(state.div, 0x12);
+    }
+
+    #[test]
+    fn tima_reload_is_delayed() {
+        let mut timer = Timer::new();
+        timer.write_tac(0x05);
+        timer.tima = 0xFF;
+        timer.tma = 0x42;
+
+        // TIMA overflows to 0 and stays there for a machine cycle
+        assert_eq!(step_machine_cycles(&mut timer, 16), 0);
+        assert_eq!(timer.tima, 0);
+        assert!(timer.state().reload_pending);
+
+        // writes during that cycle lose to the reload
+        timer.write_tima(0x10);
+
+        let interrupts = step_machine_cycles(&mut timer, 4);
+        assert_eq!(interrupts, u8::from(Interrupt::Timer));
+        assert_eq!(timer.tima, 0x42);
+        assert!(!timer.state().reload_pending);
+    }
+
+    #[test]
+    fn div_write_is_a_falling_edge() {
+        let mut timer = Timer::new();
+        timer.write_tac(0x05);
+
+        // bit 3 of the counter is set
+        step_machine_cycles(&mut timer, 8);
+        timer.reset_div();
+        assert_eq!(timer.tima, 1);
+        assert_eq!(timer.div(), 0);
+
+        // and now it's clear
+        step_machine_cycles(&mut timer, 4);
+        timer.reset_div();
+        assert_eq!(timer.tima, 1);
+    }
+
+    #[test]
+    fn tac_write_is_a_falling_edge() {
+        for &(tac, tima) in &[(0x01, 1), (0x04, 1), (0x05, 0), (0x07, 1)] {
+            let mut timer = Timer::new();
+            timer.write_tac(0x05);
+
+            step_machine_cycles(&mut timer, 8);
+            timer.write_tac(tac);
+            assert_eq!(timer.tima, tima, "TAC {:02X}", tac);
+        }
+    }
+}
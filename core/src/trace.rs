@@ -0,0 +1,83 @@
+use crate::cpu::Registers;
+use alloc::boxed::Box;
+use core::fmt;
+
+pub type TraceSink = Box<dyn FnMut(&TraceLine)>;
+
+// the CPU as it was just before an instruction executed
+#[derive(Clone, Copy, Debug)]
+pub struct TraceLine {
+    pub registers: Registers,
+    // the opcode and the three bytes following it
+    pub memory: [u8; 4],
+    pub cycles: usize,
+}
+
+// the format used by Gameboy Doctor, so logs can be diffed against other emulators
+impl fmt::Display for TraceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let registers = &self.registers;
+
+        write!(
+            f,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers.a,
+            registers.f.bits(),
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+            registers.sp,
+            registers.pc,
+            self.memory[0],
+            self.memory[1],
+            self.memory[2],
+            self.memory[3],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::console;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn gameboy_doctor_lines() {
+        let program = [
+            0x3E, 0x42, // LD A,0x42
+            0x06, 0x01, // LD B,0x01
+            0x18, 0xFE, // JR -2
+        ];
+        let mut console = console(&program, false);
+
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        console.set_trace(Box::new({
+            let lines = Rc::clone(&lines);
+            move |line| lines.borrow_mut().push((line.to_string(), line.cycles))
+        }));
+
+        for _ in 0..4 {
+            console.step();
+        }
+
+        let lines = lines.borrow();
+        let text: Vec<_> = lines.iter().map(|(text, _)| text.as_str()).collect();
+        assert_eq!(
+            text,
+            [
+                "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:3E,42,06,01",
+                "A:42 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 PCMEM:06,01,18,FE",
+                "A:42 F:B0 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0104 PCMEM:18,FE,00,00",
+                "A:42 F:B0 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0104 PCMEM:18,FE,00,00",
+            ]
+        );
+
+        // the cycles run before each instruction
+        let cycles: Vec<_> = lines.iter().map(|&(_, cycles)| cycles).collect();
+        assert_eq!(cycles, [0, 8, 16, 28]);
+    }
+}
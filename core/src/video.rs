@@ -1,10 +1,55 @@
-// TODO: 8x16 Sprites
-// TODO: Scrolling
-// TODO: Window
 use crate::interrupts::Interrupt;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use alloc::{boxed::Box, vec, vec::Vec};
 use bit_field::BitField;
 
-#[derive(Clone, Copy, Debug)]
+// the green tinted LCD of the original DMG, as RGBA
+pub const DMG_PALETTE: [[u8; 4]; 4] = [
+    [0x9B, 0xBC, 0x0F, 0xFF],
+    [0x8B, 0xAC, 0x0F, 0xFF],
+    [0x30, 0x62, 0x30, 0xFF],
+    [0x0F, 0x38, 0x0F, 0xFF],
+];
+
+// built in palettes for the RGBA framebuffer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PalettePreset {
+    Dmg,
+    // evenly spaced in perceived lightness (CIE L* 100, 67, 33, 0)
+    Grayscale,
+    // yellow on black, the pairing most legible to low vision users
+    HighContrast,
+    // the grey-olive LCD of the Game Boy Pocket
+    Pocket,
+}
+
+impl PalettePreset {
+    pub fn colours(self) -> [[u8; 4]; 4] {
+        match self {
+            PalettePreset::Dmg => DMG_PALETTE,
+            PalettePreset::Grayscale => [
+                [0xFF, 0xFF, 0xFF, 0xFF],
+                [0xA2, 0xA2, 0xA2, 0xFF],
+                [0x4E, 0x4E, 0x4E, 0xFF],
+                [0x00, 0x00, 0x00, 0xFF],
+            ],
+            PalettePreset::HighContrast => [
+                [0xFF, 0xFF, 0x00, 0xFF],
+                [0xA2, 0xA2, 0x00, 0xFF],
+                [0x4E, 0x4E, 0x00, 0xFF],
+                [0x00, 0x00, 0x00, 0xFF],
+            ],
+            PalettePreset::Pocket => [
+                [0xC4, 0xCF, 0xA1, 0xFF],
+                [0x8B, 0x95, 0x6D, 0xFF],
+                [0x4D, 0x53, 0x3C, 0xFF],
+                [0x1F, 0x1F, 0x1F, 0xFF],
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Mode {
     HBlank = 0,
     VBlank = 1,
@@ -12,7 +57,7 @@ pub enum Mode {
     VRAMRead = 3,
 }
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum Shade {
     White = 0,
     LightGrey = 1,
@@ -26,15 +71,18 @@ impl Default for Shade {
     }
 }
 
+#[derive(Clone, Copy, Default)]
 pub struct Palettes {
-    pub bgp: Vec<Shade>,
-    pub obp0: Vec<Shade>,
-    pub obp1: Vec<Shade>,
+    pub bgp: [Shade; 4],
+    // colour 0 is transparent for sprites, so has no shade
+    pub obp0: [Option<Shade>; 4],
+    pub obp1: [Option<Shade>; 4],
 }
 
 #[derive(Clone, Copy)]
 pub struct Tile {
-    pub pixels: [usize; 64],
+    // colour indices 0-3
+    pub pixels: [u8; 64],
 }
 
 impl Default for Tile {
@@ -64,10 +112,111 @@ pub struct Sprite {
     pub y_flip: bool,
     pub x_flip: bool,
     pub palette: u8,
+    // CGB only
+    pub colour_palette: u8,
+    pub bank: u8,
+}
+
+// how a background tile is drawn in CGB mode, from the tile map in VRAM bank 1
+#[derive(Clone, Copy, Default)]
+struct TileAttributes {
+    palette: usize,
+    bank: usize,
+    x_flip: bool,
+    y_flip: bool,
+    priority: bool,
+}
+
+impl From<u8> for TileAttributes {
+    fn from(value: u8) -> Self {
+        TileAttributes {
+            palette: usize::from(value.get_bits(0..3)),
+            bank: usize::from(value.get_bit(3)),
+            x_flip: value.get_bit(5),
+            y_flip: value.get_bit(6),
+            priority: value.get_bit(7),
+        }
+    }
+}
+
+// CGB colour palette RAM, accessed a byte at a time through an index and a data register
+#[derive(Clone, Copy)]
+pub struct ColourPalettes {
+    index: u8,
+    // 8 palettes of 4 RGB555 colours
+    colours: [u16; 32],
+}
+
+impl ColourPalettes {
+    fn new() -> Self {
+        ColourPalettes {
+            index: 0,
+            colours: [0; 32],
+        }
+    }
+
+    fn read_index(&self) -> u8 {
+        self.index | 0x40
+    }
+
+    fn write_index(&mut self, value: u8) {
+        self.index = value & 0xBF;
+    }
+
+    fn read_data(&self) -> u8 {
+        let address = usize::from(self.index.get_bits(0..6));
+        self.colours[address / 2].to_le_bytes()[address % 2]
+    }
+
+    // bit 7 of the index moves it on to the next byte after every write
+    fn write_data(&mut self, value: u8) {
+        let address = usize::from(self.index.get_bits(0..6));
+
+        let mut bytes = self.colours[address / 2].to_le_bytes();
+        bytes[address % 2] = value;
+        self.colours[address / 2] = u16::from_le_bytes(bytes);
+
+        self.increment_index();
+    }
+
+    fn colour(&self, palette: usize, index: usize) -> u16 {
+        self.colours[palette * 4 + index]
+    }
+
+    fn increment_index(&mut self) {
+        if self.index.get_bit(7) {
+            let address = (self.index.get_bits(0..6) + 1) % 64;
+            self.index.set_bits(0..6, address);
+        }
+    }
+}
+
+impl Snapshot for ColourPalettes {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_u8(self.index);
+
+        for colour in self.colours.iter() {
+            state.write_bytes(&colour.to_le_bytes());
+        }
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.index = state.read_u8()?;
+
+        for colour in self.colours.iter_mut() {
+            let mut bytes = [0; 2];
+            state.read_bytes(&mut bytes)?;
+            *colour = u16::from_le_bytes(bytes);
+        }
+
+        Ok(())
+    }
 }
 
 pub struct Video {
-    vram: [u8; 8192],
+    // bank 1 is only used in CGB mode
+    vram: [[u8; 8192]; 2],
+    vram_bank: usize,
     oam: [u8; 160],
 
     pub lcdc: u8,
@@ -76,29 +225,49 @@ pub struct Video {
     pub scx: u8,
     pub ly: u8,
     pub lyc: u8,
-    pub bgp: u8,
-    pub obp0: u8,
-    pub obp1: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
     pub wy: u8,
     pub wx: u8,
 
+    window_line: u8,
+    rendering: bool,
+    frame_skip: usize,
+    // frames completed since the frame skip was set, only every (frame_skip + 1)th is drawn
+    frame_count: usize,
+    frame_skipped: bool,
+    stat_line: bool,
+    frame_ready: bool,
     mode_cycles: usize,
     pub mode: Mode,
 
     framebuffer: [Shade; 160 * 144],
+    // RGB555, only drawn in CGB mode
+    colour_framebuffer: [u16; 160 * 144],
 
-    tiles: [Tile; 384],
+    // decoded from each VRAM bank, bank 1 is only used in CGB mode
+    tiles: [[Tile; 384]; 2],
     sprites: [Sprite; 40],
+    palettes: Palettes,
+
+    cgb_mode: bool,
+    background_colours: ColourPalettes,
+    sprite_colours: ColourPalettes,
+
+    hblank_callback: Option<Box<dyn FnMut(u8)>>,
 }
 
 impl Video {
     pub fn new() -> Self {
         Video {
-            vram: [0; 8192],
+            vram: [[0; 8192]; 2],
+            vram_bank: 0,
             oam: [0; 160],
 
             lcdc: 0,
-            stat: 0,
+            // LY and LYC both start at 0, so they already match
+            stat: 0b0000_0100,
             scy: 0,
             scx: 0,
             ly: 0,
@@ -109,24 +278,36 @@ impl Video {
             wy: 0,
             wx: 0,
 
+            window_line: 0,
+            rendering: true,
+            frame_skip: 0,
+            frame_count: 0,
+            frame_skipped: false,
+            stat_line: false,
+            frame_ready: false,
             mode_cycles: 0,
-            mode: Mode::OAMRead,
+            mode: Mode::HBlank,
 
             framebuffer: [Shade::White; 160 * 144],
+            colour_framebuffer: [0x7FFF; 160 * 144],
 
-            tiles: [Tile::default(); 384],
+            tiles: [[Tile::default(); 384]; 2],
             sprites: [Sprite::default(); 40],
+            palettes: Palettes::default(),
+
+            cgb_mode: false,
+            background_colours: ColourPalettes::new(),
+            sprite_colours: ColourPalettes::new(),
+            hblank_callback: None,
         }
     }
 }
 
 impl Video {
-    pub fn step(&mut self, cycles: usize) -> Vec<Interrupt> {
-        let mut interrupts = vec![];
+    pub fn step(&mut self, cycles: usize) -> u8 {
+        let mut interrupts = 0;
 
         if !self.display_enabled() {
-            self.mode = Mode::HBlank;
-            self.ly = 0;
             return interrupts;
         }
 
@@ -144,126 +325,270 @@ impl Video {
                     self.mode_cycles -= 172;
                     self.mode = Mode::HBlank;
 
-                    if self.hblank_interrupt_enabled() {
-                        interrupts.push(Interrupt::LCDStat);
-                    }
-
                     // draw line
                     self.render_scanline();
+
+                    if let Some(callback) = &mut self.hblank_callback {
+                        callback(self.ly);
+                    }
                 }
             }
             Mode::HBlank => {
                 if self.mode_cycles >= 204 {
                     self.mode_cycles -= 204;
-                    self.ly += 1;
+                    self.set_ly(self.ly + 1);
 
-                    if self.coincidence_flag() && self.coincidence_interrupt_enabled() {
-                        interrupts.push(Interrupt::LCDStat);
-                    }
-
-                    if self.ly == 143 {
+                    if self.ly == 144 {
                         self.mode = Mode::VBlank;
-                        interrupts.push(Interrupt::VBlank);
-
-                        if self.vblank_interrupt_enabled() {
-                            interrupts.push(Interrupt::LCDStat);
-                        }
+                        self.frame_ready = true;
+                        self.frame_skipped = self.frame_count != 0;
+                        self.frame_count = (self.frame_count + 1) % (self.frame_skip + 1);
+                        interrupts |= u8::from(Interrupt::VBlank);
                     } else {
                         self.mode = Mode::OAMRead;
-
-                        if self.oam_interrupt_enabled() {
-                            interrupts.push(Interrupt::LCDStat);
-                        }
                     }
                 }
             }
             Mode::VBlank => {
                 if self.mode_cycles >= 456 {
                     self.mode_cycles -= 456;
-                    self.ly += 1;
 
-                    if self.coincidence_flag() && self.coincidence_interrupt_enabled() {
-                        interrupts.push(Interrupt::LCDStat);
-                    }
-
-                    if self.ly > 153 {
+                    if self.ly == 153 {
                         self.mode = Mode::OAMRead;
-
-                        if self.oam_interrupt_enabled() {
-                            interrupts.push(Interrupt::LCDStat);
-                        }
-
-                        self.ly = 0;
+                        self.window_line = 0;
+                        self.set_ly(0);
+                    } else {
+                        self.set_ly(self.ly + 1);
                     }
                 }
             }
         }
 
+        if self.update_stat_line() {
+            interrupts |= u8::from(Interrupt::LCDStat);
+        }
+
         interrupts
     }
 
+    // the STAT interrupt is raised on the rising edge of all the enabled sources ORed together,
+    // so a source becoming active while another already is doesn't fire it again
+    fn update_stat_line(&mut self) -> bool {
+        let stat_line = (self.hblank_interrupt_enabled() && matches!(self.mode, Mode::HBlank))
+            || (self.vblank_interrupt_enabled() && matches!(self.mode, Mode::VBlank))
+            || (self.oam_interrupt_enabled() && matches!(self.mode, Mode::OAMRead))
+            || (self.coincidence_interrupt_enabled() && self.coincidence_flag());
+
+        let rising_edge = stat_line && !self.stat_line;
+        self.stat_line = stat_line;
+
+        rising_edge
+    }
+
+    // called with LY on entering HBlank, after the line has been drawn
+    pub fn set_hblank_callback(&mut self, callback: Option<Box<dyn FnMut(u8)>>) {
+        self.hblank_callback = callback;
+    }
+
+    pub fn take_hblank_callback(&mut self) -> Option<Box<dyn FnMut(u8)>> {
+        self.hblank_callback.take()
+    }
+
+    // how far through the current 456 dot line the PPU is
+    pub fn dot_in_line(&self) -> usize {
+        match self.mode {
+            Mode::OAMRead | Mode::VBlank => self.mode_cycles,
+            Mode::VRAMRead => 80 + self.mode_cycles,
+            Mode::HBlank => 80 + 172 + self.mode_cycles,
+        }
+    }
+
+    // when disabled the framebuffer isn't updated, but everything else keeps time
+    pub fn set_rendering(&mut self, rendering: bool) {
+        self.rendering = rendering;
+    }
+
+    // skipped frames still keep time and raise interrupts, they just aren't drawn
+    pub fn set_frame_skip(&mut self, frame_skip: usize) {
+        self.frame_skip = frame_skip;
+        self.frame_count = 0;
+    }
+
+    pub fn frame_skip(&self) -> usize {
+        self.frame_skip
+    }
+
+    // whether the frame that last reached VBlank was skipped rather than drawn
+    pub fn frame_skipped(&self) -> bool {
+        self.frame_skipped
+    }
+
+    // the window is only drawn once the current line reaches WY and WX is on screen
+    fn window_visible(&self) -> bool {
+        self.background_drawn() && self.window_enabled() && self.ly >= self.wy && self.wx <= 166
+    }
+
+    // on CGB clearing LCDC bit 0 doesn't hide the background, it only loses priority over sprites
+    fn background_drawn(&self) -> bool {
+        self.background_enabled() || self.cgb_mode
+    }
+
     fn render_scanline(&mut self) {
-        let palettes = self.palettes();
-        let background_tile_map = self.background_tile_map();
+        if !self.rendering || self.frame_count != 0 {
+            if self.window_visible() {
+                self.window_line += 1;
+            }
+
+            return;
+        }
+
+        let palettes = self.palettes;
+        let background_tile_map = self.background_tile_map_display();
 
         let line = self.ly;
         let framebuffer_offset = usize::from(line) * 160;
 
-        let mut scanline = vec![std::usize::MAX; 160];
+        let window_tile_map = if self.window_visible() {
+            Some(self.window_tile_map_display())
+        } else {
+            None
+        };
+
+        // background colour index of each pixel, a disabled background counts as colour 0
+        // so that sprites behind it are always visible
+        let mut scanline = [0; 160];
+        // CGB tiles which are drawn over sprites whatever the sprite's priority
+        let mut background_priority = [false; 160];
 
         for x in 0..160usize {
             let framebuffer_index = framebuffer_offset + x;
 
-            if self.background_enabled() {
-                let background_map_index = (usize::from(line) * 256) + x;
-                let pixel = background_tile_map[background_map_index];
+            if self.background_drawn() {
+                let (pixel, attributes) = match &window_tile_map {
+                    // the window's x position is offset by 7 pixels
+                    Some(window_tile_map) if x + 7 >= usize::from(self.wx) => {
+                        let window_x = x + 7 - usize::from(self.wx);
+                        let window_y = usize::from(self.window_line);
+
+                        self.tile_map_pixel(window_tile_map, window_x, window_y)
+                    }
+                    _ => {
+                        // the background map is 256x256 and wraps around in both directions
+                        let background_y = usize::from(line.wrapping_add(self.scy));
+                        let background_x = usize::from((x as u8).wrapping_add(self.scx));
+
+                        self.tile_map_pixel(&background_tile_map, background_x, background_y)
+                    }
+                };
+
+                if self.background_enabled() {
+                    scanline[x] = pixel;
+                    background_priority[x] = attributes.priority;
+                }
 
-                scanline[x] = pixel;
                 self.framebuffer[framebuffer_index] = palettes.bgp[pixel];
+                self.colour_framebuffer[framebuffer_index] =
+                    self.background_colours.colour(attributes.palette, pixel);
             } else {
                 self.framebuffer[framebuffer_index] = Shade::White;
             }
         }
 
+        // the window keeps its own line counter which only advances on lines where it was drawn
+        if window_tile_map.is_some() {
+            self.window_line += 1;
+        }
+
         if self.sprites_enabled() {
-            for sprite in self
-                .sprites
-                .as_ref()
-                .iter()
-                .filter(|s| s.y > 0 && s.y < 160)
-                .filter(|s| (s.y as i16 - 16) <= line as i16 && (s.y as i16 - 16) + 8 > line as i16)
-            {
-                let tile = &self.tiles[usize::from(sprite.tile)];
+            let sprite_height = self.sprite_height();
+
+            // (x, OAM index) of the sprites on this line, kept on the stack
+            let mut sprites = [(0u8, 0usize); 40];
+            let mut count = 0;
+
+            for (index, s) in self.sprites.iter().enumerate() {
+                // Y is offset by 16, so sprites can hang off the top of the screen
+                let top = i16::from(s.y) - 16;
+                if top <= i16::from(line) && top + i16::from(sprite_height) > i16::from(line) {
+                    sprites[count] = (s.x, index);
+                    count += 1;
+                }
+            }
+
+            // on DMG the sprite with the lowest X is drawn on top, ties go to the lowest OAM index.
+            // CGB only goes by OAM index, which is the order they were found in
+            if !self.cgb_mode {
+                sprites[..count].sort_unstable();
+            }
+
+            // pixels already claimed by a higher priority sprite
+            let mut sprite_pixels = [false; 160];
+
+            for &(_, index) in &sprites[..count] {
+                let sprite = &self.sprites[index];
                 let palette = if sprite.palette == 0 {
                     &palettes.obp0
                 } else {
                     &palettes.obp1
                 };
 
-                let pixel_y_offset = usize::from(line - (sprite.y - 16));
+                let pixel_y_offset = (i16::from(line) - (i16::from(sprite.y) - 16)) as usize;
                 let pixel_y_offset = if sprite.y_flip {
-                    7 - pixel_y_offset
+                    usize::from(sprite_height) - 1 - pixel_y_offset
                 } else {
                     pixel_y_offset
                 };
 
+                // 8x16 sprites span two consecutive tiles, the low bit of the tile index is ignored
+                let tile_index = if sprite_height == 16 {
+                    sprite.tile & 0xFE
+                } else {
+                    sprite.tile
+                };
+
+                let tile_index = usize::from(tile_index) + pixel_y_offset / 8;
+                let pixel_y_offset = pixel_y_offset % 8;
+
+                let bank = if self.cgb_mode {
+                    usize::from(sprite.bank)
+                } else {
+                    0
+                };
+
                 for x in 0..8usize {
                     let pixel_x_offset = if sprite.x_flip { 7 - x } else { x };
-                    let pixel_index = pixel_y_offset * 8 + pixel_x_offset;
-                    let pixel = tile.pixels[pixel_index];
+                    let pixel = self.tile_pixel(bank, tile_index, pixel_x_offset, pixel_y_offset);
 
-                    if pixel == 0 {
+                    let shade = match palette[pixel] {
+                        Some(shade) => shade,
+                        None => continue,
+                    };
+
+                    // X is offset by 8, so sprites can hang off either side of the screen
+                    let framebuffer_x = i16::from(sprite.x) - 8 + x as i16;
+                    if !(0..160).contains(&framebuffer_x) {
                         continue;
                     }
 
-                    let framebuffer_x = usize::from(sprite.x - 8) + x;
+                    let framebuffer_x = framebuffer_x as usize;
                     let framebuffer_index = framebuffer_offset + framebuffer_x;
 
-                    if sprite.priority == Priority::Behind && scanline[framebuffer_x] != 0 {
+                    if sprite_pixels[framebuffer_x] {
+                        continue;
+                    }
+
+                    sprite_pixels[framebuffer_x] = true;
+
+                    if (sprite.priority == Priority::Behind || background_priority[framebuffer_x])
+                        && scanline[framebuffer_x] != 0
+                    {
                         continue;
                     }
 
-                    self.framebuffer[framebuffer_index] = palette[pixel];
+                    self.framebuffer[framebuffer_index] = shade;
+                    self.colour_framebuffer[framebuffer_index] = self
+                        .sprite_colours
+                        .colour(usize::from(sprite.colour_palette), pixel);
                 }
             }
         }
@@ -271,24 +596,171 @@ impl Video {
 }
 
 impl Video {
-    pub fn read_byte(&self, address: u16) -> u8 {
-        let address = usize::from(address);
+    pub fn read_stat(&self) -> u8 {
+        let mut stat = self.stat;
+        stat.set_bits(0..2, self.mode as u8);
+
+        // bit 7 is unused and always reads as 1
+        stat | 0x80
+    }
+
+    // only the interrupt enables are writable, the mode and coincidence flag are read only
+    pub fn write_stat(&mut self, value: u8) {
+        self.stat.set_bits(3..7, value.get_bits(3..7));
+    }
+
+    // true once per frame, after the frame has been completely drawn
+    pub fn take_frame_ready(&mut self) -> bool {
+        core::mem::replace(&mut self.frame_ready, false)
+    }
+
+    pub fn set_lcdc(&mut self, value: u8) {
+        let was_enabled = self.display_enabled();
+        self.lcdc = value;
+
+        if was_enabled && !self.display_enabled() {
+            // LY is held at 0 whilst the LCD is off
+            self.mode = Mode::HBlank;
+            self.mode_cycles = 0;
+            self.window_line = 0;
+            self.set_ly(0);
+        } else if !was_enabled && self.display_enabled() {
+            // the first line starts from the beginning of OAM search
+            self.mode = Mode::OAMRead;
+            self.mode_cycles = 0;
+            self.window_line = 0;
+            self.stat_line = false;
+            self.set_ly(0);
+        }
+    }
+
+    pub fn bgp(&self) -> u8 {
+        self.bgp
+    }
+
+    pub fn obp0(&self) -> u8 {
+        self.obp0
+    }
+
+    pub fn obp1(&self) -> u8 {
+        self.obp1
+    }
+
+    // the decoded palettes are cached and only updated when the registers are written
+    pub fn set_bgp(&mut self, value: u8) {
+        self.bgp = value;
+        self.palettes.bgp = Video::palette(value);
+    }
+
+    pub fn set_obp0(&mut self, value: u8) {
+        self.obp0 = value;
+        self.palettes.obp0 = Video::object_palette(value);
+    }
+
+    pub fn set_obp1(&mut self, value: u8) {
+        self.obp1 = value;
+        self.palettes.obp1 = Video::object_palette(value);
+    }
+
+    // the CPU can't see OAM whilst the PPU is searching it or drawing
+    pub fn oam_blocked(&self) -> bool {
+        matches!(self.mode, Mode::OAMRead | Mode::VRAMRead)
+    }
+
+    // on DMG a 16-bit inc/dec of a pointer into OAM during mode 2 corrupts the row being searched
+    #[cfg(feature = "oam_bug")]
+    pub fn corrupt_oam(&mut self, address: u16) {
+        if self.cgb_mode {
+            return;
+        }
+
+        if !matches!(self.mode, Mode::OAMRead) || !(0xFE00..=0xFEFF).contains(&address) {
+            return;
+        }
+
+        // the search reads one 8 byte row every machine cycle, the first row is never corrupted
+        let row = self.mode_cycles / 4 * 8;
+        if row == 0 || row >= self.oam.len() {
+            return;
+        }
+
+        let word = |i: usize| u16::from_le_bytes([self.oam[i], self.oam[i + 1]]);
+
+        let a = word(row);
+        let b = word(row - 8);
+        let c = word(row - 4);
+
+        let mut bytes = [0; 8];
+        bytes[0..2].copy_from_slice(&(((a ^ c) & (b ^ c)) ^ c).to_le_bytes());
+        bytes[2..8].copy_from_slice(&self.oam[row - 6..row]);
+
+        for (offset, value) in bytes.iter().enumerate() {
+            self.write_oam(0xFE00 + (row + offset) as u16, *value);
+        }
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    // VBK and the colour palette registers, which are only present in CGB mode
+    pub fn read_cgb_register(&self, address: u16) -> u8 {
+        if !self.cgb_mode {
+            return 0xFF;
+        }
 
         match address {
-            0x8000..=0x9FFF => {
-                if let Mode::VRAMRead = self.mode {
-                    return 0xFF;
-                }
+            0xFF4F => 0xFE | self.vram_bank as u8,
+            0xFF68 => self.background_colours.read_index(),
+            0xFF69 if matches!(self.mode, Mode::VRAMRead) => 0xFF,
+            0xFF69 => self.background_colours.read_data(),
+            0xFF6A => self.sprite_colours.read_index(),
+            0xFF6B if matches!(self.mode, Mode::VRAMRead) => 0xFF,
+            0xFF6B => self.sprite_colours.read_data(),
+            _ => unreachable!(),
+        }
+    }
 
-                self.vram[address - 0x8000]
-            }
-            0xFE00..=0xFE9F => {
-                if let Mode::OAMRead | Mode::VRAMRead = self.mode {
-                    return 0xFF;
-                }
+    pub fn write_cgb_register(&mut self, address: u16, value: u8) {
+        if !self.cgb_mode {
+            return;
+        }
 
-                self.oam[address - 0xFE00]
+        match address {
+            0xFF4F => self.vram_bank = usize::from(value.get_bit(0)),
+            0xFF68 => self.background_colours.write_index(value),
+            // palette RAM can't be written whilst drawing, but the index still moves on
+            0xFF69 if matches!(self.mode, Mode::VRAMRead) => {
+                self.background_colours.increment_index()
             }
+            0xFF69 => self.background_colours.write_data(value),
+            0xFF6A => self.sprite_colours.write_index(value),
+            0xFF6B if matches!(self.mode, Mode::VRAMRead) => self.sprite_colours.increment_index(),
+            0xFF6B => self.sprite_colours.write_data(value),
+            _ => unreachable!(),
+        }
+    }
+
+    // the CPU's view of VRAM and OAM, which the PPU locks whilst it reads them
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0x8000..=0x9FFF if matches!(self.mode, Mode::VRAMRead) => 0xFF,
+            0xFE00..=0xFE9F if self.oam_blocked() => 0xFF,
+            _ => self.peek_byte(address),
+        }
+    }
+
+    // reads VRAM and OAM even whilst the PPU has them locked
+    pub fn peek_byte(&self, address: u16) -> u8 {
+        let address = usize::from(address);
+
+        match address {
+            0x8000..=0x9FFF => self.vram[self.vram_bank][address - 0x8000],
+            0xFE00..=0xFE9F => self.oam[address - 0xFE00],
             _ => unreachable!(),
         }
     }
@@ -300,10 +772,10 @@ impl Video {
                     return;
                 }
 
-                self.write_vram(address, value)
+                self.write_vram(self.vram_bank, address, value)
             }
             0xFE00..=0xFE9F => {
-                if let Mode::OAMRead | Mode::VRAMRead = self.mode {
+                if self.oam_blocked() {
                     return;
                 }
 
@@ -313,18 +785,18 @@ impl Video {
         }
     }
 
-    fn write_vram(&mut self, address: u16, value: u8) {
+    fn write_vram(&mut self, bank: usize, address: u16, value: u8) {
         let address = usize::from(address);
 
         let index = address - 0x8000;
-        self.vram[index] = value;
+        self.vram[bank][index] = value;
 
         if address > 0x97FF {
             return; // background tile map addresses
         }
 
         let tile_index = index / 16;
-        let tile = &mut self.tiles[tile_index];
+        let tile = &mut self.tiles[bank][tile_index];
 
         let byte = index % 16;
         let row = byte / 2;
@@ -339,7 +811,18 @@ impl Video {
         }
     }
 
-    fn write_oam(&mut self, address: u16, value: u8) {
+    // sets the power-on contents of both VRAM banks, one after the other, and OAM
+    pub fn fill_ram(&mut self, vram: &[u8], oam: &[u8]) {
+        for (i, value) in vram.iter().enumerate() {
+            self.write_vram(i / 0x2000, 0x8000 + (i % 0x2000) as u16, *value);
+        }
+
+        for (i, value) in oam.iter().enumerate() {
+            self.write_oam(0xFE00 + i as u16, *value);
+        }
+    }
+
+    pub fn write_oam(&mut self, address: u16, value: u8) {
         let address = usize::from(address);
 
         let index = address - 0xFE00;
@@ -363,12 +846,120 @@ impl Video {
                 sprite.y_flip = value.get_bit(6);
                 sprite.x_flip = value.get_bit(5);
                 sprite.palette = if value.get_bit(4) { 1 } else { 0 };
+                sprite.colour_palette = value.get_bits(0..3);
+                sprite.bank = u8::from(value.get_bit(3));
             }
             _ => unreachable!(),
         }
     }
 }
 
+impl Snapshot for Video {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.vram[0]);
+        state.write_bytes(&self.vram[1]);
+        state.write_usize(self.vram_bank);
+        state.write_bytes(&self.oam);
+
+        state.write_u8(self.lcdc);
+        state.write_u8(self.stat);
+        state.write_u8(self.scy);
+        state.write_u8(self.scx);
+        state.write_u8(self.ly);
+        state.write_u8(self.lyc);
+        state.write_u8(self.bgp);
+        state.write_u8(self.obp0);
+        state.write_u8(self.obp1);
+        state.write_u8(self.wy);
+        state.write_u8(self.wx);
+
+        state.write_u8(self.window_line);
+        state.write_bool(self.stat_line);
+        state.write_usize(self.mode_cycles);
+        state.write_u8(self.mode as u8);
+
+        for shade in self.framebuffer.iter() {
+            state.write_u8(*shade as u8);
+        }
+
+        for colour in self.colour_framebuffer.iter() {
+            state.write_bytes(&colour.to_le_bytes());
+        }
+
+        state.write_bool(self.cgb_mode);
+        self.background_colours.save(state);
+        self.sprite_colours.save(state);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        for bank in 0..2 {
+            let mut vram = [0; 8192];
+            state.read_bytes(&mut vram)?;
+
+            for (offset, value) in vram.iter().enumerate() {
+                self.write_vram(bank, 0x8000 + offset as u16, *value);
+            }
+        }
+
+        self.vram_bank = match state.read_usize()? {
+            bank @ 0..=1 => bank,
+            _ => return Err(StateError::InvalidValue),
+        };
+
+        let mut oam = [0; 160];
+        state.read_bytes(&mut oam)?;
+
+        for (offset, value) in oam.iter().enumerate() {
+            self.write_oam(0xFE00 + offset as u16, *value);
+        }
+
+        self.lcdc = state.read_u8()?;
+        self.stat = state.read_u8()?;
+        self.scy = state.read_u8()?;
+        self.scx = state.read_u8()?;
+        self.ly = state.read_u8()?;
+        self.lyc = state.read_u8()?;
+        self.set_bgp(state.read_u8()?);
+        self.set_obp0(state.read_u8()?);
+        self.set_obp1(state.read_u8()?);
+        self.wy = state.read_u8()?;
+        self.wx = state.read_u8()?;
+
+        self.window_line = state.read_u8()?;
+        self.stat_line = state.read_bool()?;
+        self.mode_cycles = state.read_usize()?;
+        self.mode = match state.read_u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OAMRead,
+            3 => Mode::VRAMRead,
+            _ => return Err(StateError::InvalidValue),
+        };
+
+        for shade in self.framebuffer.iter_mut() {
+            *shade = match state.read_u8()? {
+                0 => Shade::White,
+                1 => Shade::LightGrey,
+                2 => Shade::DarkGrey,
+                3 => Shade::Black,
+                _ => return Err(StateError::InvalidValue),
+            };
+        }
+
+        for colour in self.colour_framebuffer.iter_mut() {
+            let mut bytes = [0; 2];
+            state.read_bytes(&mut bytes)?;
+            *colour = u16::from_le_bytes(bytes);
+        }
+
+        self.cgb_mode = state.read_bool()?;
+        self.background_colours.load(state)?;
+        self.sprite_colours.load(state)?;
+
+        Ok(())
+    }
+}
+
 #[allow(non_camel_case_types)]
 pub enum BackgroundAddressMode {
     x8000,
@@ -386,6 +977,18 @@ impl Video {
         self.lcdc.get_bit(7)
     }
 
+    fn window_tile_map_display(&self) -> BackgroundTileMap {
+        if self.lcdc.get_bit(6) {
+            BackgroundTileMap::x9C00
+        } else {
+            BackgroundTileMap::x9800
+        }
+    }
+
+    fn window_enabled(&self) -> bool {
+        self.lcdc.get_bit(5)
+    }
+
     fn background_address_mode(&self) -> BackgroundAddressMode {
         if self.lcdc.get_bit(4) {
             BackgroundAddressMode::x8000
@@ -402,6 +1005,14 @@ impl Video {
         }
     }
 
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc.get_bit(2) {
+            16
+        } else {
+            8
+        }
+    }
+
     fn sprites_enabled(&self) -> bool {
         self.lcdc.get_bit(1)
     }
@@ -427,67 +1038,949 @@ impl Video {
     }
 
     pub fn coincidence_flag(&self) -> bool {
-        self.lyc == self.ly
+        self.stat.get_bit(2)
+    }
+
+    // LYC=LY is compared whenever either of them changes
+    fn update_coincidence_flag(&mut self) {
+        self.stat.set_bit(2, self.ly == self.lyc);
+    }
+
+    fn set_ly(&mut self, ly: u8) {
+        self.ly = ly;
+        self.update_coincidence_flag();
+    }
+
+    pub fn set_lyc(&mut self, lyc: u8) {
+        self.lyc = lyc;
+        self.update_coincidence_flag();
     }
 }
 
 impl Video {
-    fn palette(&self, reg: u8) -> Vec<Shade> {
-        [0..=1, 2..=3, 4..=5, 6..=7]
-            .into_iter()
-            .map(|range| match reg.get_bits(range.clone()) {
+    fn palette(reg: u8) -> [Shade; 4] {
+        let mut palette = [Shade::White; 4];
+
+        for (i, shade) in palette.iter_mut().enumerate() {
+            *shade = match reg.get_bits(i * 2..i * 2 + 2) {
                 0 => Shade::White,
                 1 => Shade::LightGrey,
                 2 => Shade::DarkGrey,
                 3 => Shade::Black,
                 _ => unreachable!("Invalid shade"),
+            };
+        }
+
+        palette
+    }
+
+    // as palette, but the bits for colour 0 are unused
+    fn object_palette(reg: u8) -> [Option<Shade>; 4] {
+        let palette = Video::palette(reg);
+
+        [None, Some(palette[1]), Some(palette[2]), Some(palette[3])]
+    }
+
+    // samples a single pixel from the 256x256 map without decoding the whole map
+    fn tile_map_pixel(
+        &self,
+        tile_map: &BackgroundTileMap,
+        x: usize,
+        y: usize,
+    ) -> (usize, TileAttributes) {
+        let tile_map_address = match tile_map {
+            BackgroundTileMap::x9800 => 0x9800,
+            BackgroundTileMap::x9C00 => 0x9C00,
+        };
+
+        let address = tile_map_address + (y / 8) * 32 + (x / 8);
+        let tile_index = self.ppu_read_vram(0, address);
+
+        let attributes = if self.cgb_mode {
+            TileAttributes::from(self.ppu_read_vram(1, address))
+        } else {
+            TileAttributes::default()
+        };
+
+        let tile_index = match self.background_address_mode() {
+            BackgroundAddressMode::x8000 => usize::from(tile_index),
+            // the index is signed from a base of 0x9000, so 0-127 are tiles 256-383 at
+            // 0x9000-0x97FF and 128-255 (-128 to -1) are tiles 128-255 at 0x8800-0x8FFF
+            BackgroundAddressMode::x8800 => match tile_index {
+                0x00..=0x7F => 256 + usize::from(tile_index),
+                0x80..=0xFF => usize::from(tile_index),
+            },
+        };
+
+        let x = if attributes.x_flip { 7 - x % 8 } else { x % 8 };
+        let y = if attributes.y_flip { 7 - y % 8 } else { y % 8 };
+
+        let pixel = self.tile_pixel(attributes.bank, tile_index, x, y);
+
+        (pixel, attributes)
+    }
+
+    fn tile_pixel(&self, bank: usize, tile_index: usize, x: usize, y: usize) -> usize {
+        usize::from(self.tiles[bank][tile_index].pixels[y * 8 + x])
+    }
+
+    // the PPU's own view of VRAM, never blocked as it's the PPU doing the blocking,
+    // the CPU goes through read_byte instead
+    fn ppu_read_vram(&self, bank: usize, address: usize) -> u8 {
+        self.vram[bank][address - 0x8000]
+    }
+
+    pub fn framebuffer(&self) -> &[Shade] {
+        &self.framebuffer
+    }
+
+    // FNV-1a over the shades, or the RGB555 colours in CGB mode
+    pub fn frame_hash(&self) -> u64 {
+        let fnv = |hash: u64, byte: u8| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01B3);
+
+        if self.cgb_mode {
+            self.colour_framebuffer
+                .iter()
+                .fold(0xCBF2_9CE4_8422_2325, |hash, colour| {
+                    let [low, high] = colour.to_le_bytes();
+                    fnv(fnv(hash, low), high)
+                })
+        } else {
+            self.framebuffer
+                .iter()
+                .map(|shade| *shade as u8)
+                .fold(0xCBF2_9CE4_8422_2325, fnv)
+        }
+    }
+
+    pub fn background_palette(&self) -> [Shade; 4] {
+        self.palettes.bgp
+    }
+
+    // every tile in VRAM bank 0 laid out 16 tiles wide and 24 tiles high, 128x192 pixels
+    pub fn tile_atlas(&self, palette: [Shade; 4]) -> Vec<Shade> {
+        let mut atlas = vec![Shade::White; 128 * 192];
+
+        for (i, tile) in self.tiles[0].iter().enumerate() {
+            let x_offset = (i % 16) * 8;
+            let y_offset = (i / 16) * 8;
+
+            for y in 0..8 {
+                for x in 0..8 {
+                    let pixel = usize::from(tile.pixels[y * 8 + x]);
+                    atlas[(y_offset + y) * 128 + x_offset + x] = palette[pixel];
+                }
+            }
+        }
+
+        atlas
+    }
+
+    // the whole 256x256 background map, of which the screen shows the viewport
+    pub fn render_background_map(&self, palette: [Shade; 4]) -> Vec<Shade> {
+        let tile_map = self.background_tile_map_display();
+        let mut map = vec![Shade::White; 256 * 256];
+
+        for y in 0..256 {
+            for x in 0..256 {
+                map[y * 256 + x] = palette[self.tile_map_pixel(&tile_map, x, y).0];
+            }
+        }
+
+        map
+    }
+
+    // the top left corner of the screen within the background map
+    pub fn viewport(&self) -> (u8, u8) {
+        (self.scx, self.scy)
+    }
+
+    // maps each shade onto an RGBA colour from the palette, White being the first
+    // in CGB mode the palette is ignored, the colours come from the palette RAM
+    pub fn framebuffer_rgba(&self, palette: [[u8; 4]; 4]) -> Vec<u8> {
+        if self.cgb_mode {
+            return self
+                .colour_framebuffer
+                .iter()
+                .flat_map(|colour| rgb555_to_rgba(*colour).to_vec())
+                .collect();
+        }
+
+        self.framebuffer
+            .iter()
+            .flat_map(|shade| palette[*shade as usize].iter().copied())
+            .collect()
+    }
+}
+
+// scales each 5 bit channel up to 8 bits
+fn rgb555_to_rgba(colour: u16) -> [u8; 4] {
+    let channel = |shift: usize| {
+        let value = colour.get_bits(shift..shift + 5) as u8;
+        (value << 3) | (value >> 2)
+    };
+
+    [channel(0), channel(5), channel(10), 0xFF]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a background of tile 0, which is white, with the black tile 1 in the top left corner
+    fn video_with_background() -> Video {
+        let mut video = Video::new();
+        video.set_lcdc(0x91);
+        video.set_bgp(0xE4);
+
+        for byte in 0..16 {
+            video.write_vram(0, 0x8010 + byte, 0xFF);
+        }
+        video.write_vram(0, 0x9800, 1);
+
+        video
+    }
+
+    // the columns of line ly which are black after rendering it
+    fn black_columns(video: &mut Video, ly: u8) -> Vec<usize> {
+        video.ly = ly;
+        video.render_scanline();
+
+        let line = &video.framebuffer()[usize::from(ly) * 160..][..160];
+        (0..160).filter(|&x| line[x] == Shade::Black).collect()
+    }
+
+    // colours 0, 1, 2, 3, 0, 1, 2, 3 from left to right on every row
+    fn video_with_sprite_tile() -> Video {
+        let mut video = Video::new();
+        video.set_lcdc(0x82);
+        video.set_obp0(0xE4);
+
+        for row in 0..8 {
+            video.write_vram(0, 0x8010 + row * 2, 0x55);
+            video.write_vram(0, 0x8011 + row * 2, 0x33);
+        }
+
+        video
+    }
+
+    fn place_sprite(video: &mut Video, index: u16, x: u8) {
+        let address = 0xFE00 + index * 4;
+        video.write_oam(address, 16);
+        video.write_oam(address + 1, x);
+        video.write_oam(address + 2, 1);
+        video.write_oam(address + 3, 0);
+    }
+
+    #[test]
+    fn sprites_clipped_at_screen_edges() {
+        let mut video = video_with_sprite_tile();
+        place_sprite(&mut video, 0, 2);
+        place_sprite(&mut video, 1, 165);
+
+        video.render_scanline();
+
+        let line = &video.framebuffer()[0..160];
+
+        // X=2 leaves only the last two columns on screen
+        assert_eq!(line[0..3], [Shade::DarkGrey, Shade::Black, Shade::White]);
+        // X=165 leaves only the first three, the first of which is transparent
+        assert_eq!(
+            line[156..160],
+            [
+                Shade::White,
+                Shade::White,
+                Shade::LightGrey,
+                Shade::DarkGrey
+            ]
+        );
+        assert!(line[3..156].iter().all(|shade| *shade == Shade::White));
+    }
+
+    // sprite 0 at X=20 drawn with OBP0, sprite 1 at X=16 with OBP1, overlapping from column 12
+    fn overlapping_sprite(cgb_mode: bool) -> Shade {
+        let mut video = video_with_sprite_tile();
+        video.set_cgb_mode(cgb_mode);
+        video.set_obp1(0x1B);
+
+        place_sprite(&mut video, 0, 20);
+        place_sprite(&mut video, 1, 16);
+        video.write_oam(0xFE07, 0x10);
+
+        video.render_scanline();
+        video.framebuffer()[13]
+    }
+
+    #[test]
+    fn sprite_priority() {
+        // colour 1 is light grey in OBP0 and dark grey in OBP1
+        assert_eq!(overlapping_sprite(false), Shade::DarkGrey);
+        assert_eq!(overlapping_sprite(true), Shade::LightGrey);
+    }
+
+    // the LY of every STAT interrupt requested over the given number of lines
+    fn stat_interrupts(video: &mut Video, lines: usize) -> Vec<u8> {
+        let mut requested = vec![];
+
+        for _ in 0..lines * 456 / 4 {
+            if video.step(4) & u8::from(Interrupt::LCDStat) != 0 {
+                requested.push(video.ly);
+            }
+        }
+
+        requested
+    }
+
+    #[test]
+    fn stat_interrupt_on_rising_edge() {
+        let mut video = Video::new();
+        video.set_lcdc(0x80);
+        video.write_stat(0x08); // HBlank
+
+        assert_eq!(stat_interrupts(&mut video, 3), [0, 1, 2]);
+    }
+
+    #[test]
+    fn overlapping_stat_sources_interrupt_once() {
+        let mut video = Video::new();
+        video.set_lcdc(0x80);
+        video.write_stat(0x48); // HBlank and LYC=LY
+        video.set_lyc(1);
+
+        // the line stays high from line 0's HBlank, through LY=1, to the end of line 1's HBlank
+        assert_eq!(stat_interrupts(&mut video, 3), [0, 2]);
+    }
+
+    #[cfg(feature = "oam_bug")]
+    fn video_searching_row(row: usize) -> Video {
+        let mut video = Video::new();
+
+        for i in 0..160 {
+            video.write_oam(0xFE00 + i as u16, i as u8);
+        }
+
+        video.mode = Mode::OAMRead;
+        video.mode_cycles = row / 8 * 4;
+        video
+    }
+
+    #[cfg(feature = "oam_bug")]
+    #[test]
+    fn oam_bug_corrupts_row() {
+        let mut video = video_searching_row(16);
+        video.corrupt_oam(0xFE10);
+
+        // the first word is ((a ^ c) & (b ^ c)) ^ c with a = 0x1110, b = 0x0908 and c = 0x0D0C,
+        // the rest of the row is copied from the row before
+        assert_eq!(video.oam[16..24], [0x08, 0x09, 10, 11, 12, 13, 14, 15]);
+        assert_eq!(video.oam[0..16], (0..16).collect::<Vec<u8>>()[..]);
+        assert_eq!(video.oam[24..32], (24..32).collect::<Vec<u8>>()[..]);
+        assert_eq!(video.sprites[4].y, 0x08);
+    }
+
+    #[cfg(feature = "oam_bug")]
+    #[test]
+    fn oam_bug_is_dmg_only() {
+        let mut video = video_searching_row(16);
+        video.set_cgb_mode(true);
+        video.corrupt_oam(0xFE10);
+
+        assert_eq!(video.oam[16..24], [16, 17, 18, 19, 20, 21, 22, 23]);
+    }
+
+    #[test]
+    fn background_scrolls_and_wraps() {
+        let mut video = video_with_background();
+        assert_eq!(black_columns(&mut video, 0), (0..8).collect::<Vec<_>>());
+
+        video.scx = 4;
+        assert_eq!(black_columns(&mut video, 0), (0..4).collect::<Vec<_>>());
+
+        // the map wraps around horizontally
+        video.scx = 252;
+        assert_eq!(black_columns(&mut video, 0), (4..12).collect::<Vec<_>>());
+
+        // and vertically
+        video.scx = 0;
+        video.scy = 250;
+        assert_eq!(black_columns(&mut video, 5), []);
+        assert_eq!(black_columns(&mut video, 6), (0..8).collect::<Vec<_>>());
+        assert_eq!(black_columns(&mut video, 13), (0..8).collect::<Vec<_>>());
+        assert_eq!(black_columns(&mut video, 14), []);
+    }
+
+    #[test]
+    fn window_drawn_from_wx_minus_7() {
+        let mut video = video_with_background();
+        video.set_lcdc(0xF1); // window on, using the 0x9C00 map
+        video.write_vram(0, 0x9C00, 1);
+        video.wx = 87;
+        video.wy = 40;
+
+        // above WY only the background is drawn
+        assert_eq!(black_columns(&mut video, 0), (0..8).collect::<Vec<_>>());
+        assert_eq!(black_columns(&mut video, 39), []);
+
+        for ly in 40..48 {
+            assert_eq!(black_columns(&mut video, ly), (80..88).collect::<Vec<_>>());
+        }
+        assert_eq!(black_columns(&mut video, 48), []);
+
+        // the window's line counter only advances on lines it was drawn on
+        video.set_lcdc(0xD1);
+        black_columns(&mut video, 49);
+        video.set_lcdc(0xF1);
+        assert_eq!(video.window_line, 9);
+    }
+
+    // the shade of column 0 on each of the first 16 lines, with an 8x16 sprite there made of a
+    // light grey tile with a black top row above a dark grey tile
+    fn tall_sprite_column(attributes: u8) -> Vec<Shade> {
+        let mut video = Video::new();
+        video.set_lcdc(0x86);
+        video.set_obp0(0xE4);
+
+        for row in 0..8 {
+            video.write_vram(0, 0x8020 + row * 2, 0xFF);
+            video.write_vram(0, 0x8031 + row * 2, 0xFF);
+        }
+        video.write_vram(0, 0x8021, 0xFF);
+
+        video.write_oam(0xFE00, 16);
+        video.write_oam(0xFE01, 8);
+        // the low bit of the tile index is ignored
+        video.write_oam(0xFE02, 3);
+        video.write_oam(0xFE03, attributes);
+
+        (0..16)
+            .map(|ly| {
+                video.ly = ly;
+                video.render_scanline();
+                video.framebuffer()[usize::from(ly) * 160]
             })
             .collect()
     }
 
-    fn palettes(&self) -> Palettes {
-        Palettes {
-            bgp: self.palette(self.bgp),
-            obp0: self.palette(self.obp0),
-            obp1: self.palette(self.obp1),
+    #[test]
+    fn tall_sprites() {
+        let mut column = vec![Shade::Black];
+        column.extend_from_slice(&[Shade::LightGrey; 7]);
+        column.extend_from_slice(&[Shade::DarkGrey; 8]);
+        assert_eq!(tall_sprite_column(0x00), column);
+
+        // Y flip turns the whole 16 lines upside down
+        column.reverse();
+        assert_eq!(tall_sprite_column(0x40), column);
+    }
+
+    #[test]
+    fn dmg_sprite_x_priority() {
+        // column 13 is colour 1 of both sprites, which is light grey in OBP0 and dark grey in OBP1
+        let shade = |x0: u8, x1: u8| {
+            let mut video = video_with_sprite_tile();
+            video.set_obp1(0x1B);
+
+            place_sprite(&mut video, 0, x0);
+            place_sprite(&mut video, 1, x1);
+            video.write_oam(0xFE07, 0x10);
+
+            video.render_scanline();
+            video.framebuffer()[13]
+        };
+
+        // the lower X wins, whichever comes first in OAM
+        assert_eq!(shade(16, 20), Shade::LightGrey);
+        assert_eq!(shade(20, 16), Shade::DarkGrey);
+
+        // and ties go to the first in OAM
+        assert_eq!(shade(20, 20), Shade::LightGrey);
+    }
+
+    #[test]
+    fn behind_sprite_with_background_disabled() {
+        let mut video = video_with_background();
+        video.set_obp0(0xE4);
+
+        // colours 0, 1, 2, 3, 0, 1, 2, 3 over the black background tile
+        for row in 0..8 {
+            video.write_vram(0, 0x8020 + row * 2, 0x55);
+            video.write_vram(0, 0x8021 + row * 2, 0x33);
         }
+        video.write_oam(0xFE00, 16);
+        video.write_oam(0xFE01, 8);
+        video.write_oam(0xFE02, 2);
+        video.write_oam(0xFE03, 0x80); // behind the background
+
+        video.set_lcdc(0x93);
+        assert_eq!(black_columns(&mut video, 0), (0..8).collect::<Vec<_>>());
+
+        // a disabled background counts as colour 0, so the sprite shows through
+        video.set_lcdc(0x92);
+        video.render_scanline();
+        assert_eq!(
+            video.framebuffer()[0..4],
+            [
+                Shade::White,
+                Shade::LightGrey,
+                Shade::DarkGrey,
+                Shade::Black
+            ]
+        );
     }
 
-    fn background_tile_map(&self) -> Vec<usize> {
-        let mut result = vec![0; 32 * 32 * 8 * 8];
+    #[test]
+    fn rgba_uses_palette() {
+        let palette = [
+            [1, 2, 3, 4],
+            [5, 6, 7, 8],
+            [9, 10, 11, 12],
+            [13, 14, 15, 16],
+        ];
 
-        let tile_map_address = match self.background_tile_map_display() {
-            BackgroundTileMap::x9800 => 0x9800,
-            BackgroundTileMap::x9C00 => 0x9C00,
+        let mut video = video_with_background();
+        black_columns(&mut video, 0);
+
+        let rgba = video.framebuffer_rgba(palette);
+        assert_eq!(rgba.len(), 160 * 144 * 4);
+
+        // black in the top left corner, then white
+        assert_eq!(rgba[0..4], palette[3]);
+        assert_eq!(rgba[8 * 4..9 * 4], palette[0]);
+    }
+
+    // the background pixel at (x, y) of the map, decoded straight from VRAM
+    fn reference_background_pixel(video: &Video, x: usize, y: usize) -> usize {
+        let vram = &video.vram[0];
+        let tile = vram[0x1800 + (y / 8) * 32 + x / 8];
+
+        let tile_address = if video.lcdc.get_bit(4) {
+            usize::from(tile) * 16
+        } else {
+            (0x1000 + i32::from(tile as i8) * 16) as usize
         };
 
-        for i in 0..(32 * 32) {
-            let tile_index = self.vram[tile_map_address + i - 0x8000]; // don't use read_byte as this can happen during VRAM/OAMRead
+        let low = vram[tile_address + (y % 8) * 2];
+        let high = vram[tile_address + (y % 8) * 2 + 1];
+        let bit = 7 - x % 8;
 
-            let tile = match self.background_address_mode() {
-                BackgroundAddressMode::x8000 => &self.tiles[usize::from(tile_index)],
-                BackgroundAddressMode::x8800 => {
-                    let tile_index = i16::from(tile_index as i8);
-                    let tile_index = 256 + tile_index;
-                    &self.tiles[tile_index as usize]
-                }
-            };
+        usize::from(low.get_bit(bit)) | usize::from(high.get_bit(bit)) << 1
+    }
 
-            let x_offset = (i % 32) * 8;
-            let y_offset = (i / 32) * 8;
+    #[test]
+    fn background_matches_reference() {
+        for &lcdc in &[0x91, 0x81] {
+            let mut video = Video::new();
+            video.set_lcdc(lcdc);
+            video.set_bgp(0xE4);
+            video.scx = 13;
+            video.scy = 200;
+
+            for i in 0..0x1800u16 {
+                video.write_vram(0, 0x8000 + i, (u32::from(i) * 37 % 251) as u8);
+            }
+            for i in 0..0x400u16 {
+                video.write_vram(0, 0x9800 + i, (i * 7 % 256) as u8);
+            }
 
-            for x in 0..8 {
-                for y in 0..8 {
-                    let i = (x_offset + x) + ((y_offset + y) * 8 * 32);
-                    result[usize::from(i)] = tile.pixels[usize::from(y * 8 + x)];
+            for ly in 0..144 {
+                video.ly = ly;
+                video.render_scanline();
+            }
+
+            for y in 0..144 {
+                for x in 0..160 {
+                    let map_x = (x + 13) % 256;
+                    let map_y = (y + 200) % 256;
+                    let pixel = reference_background_pixel(&video, map_x, map_y);
+
+                    assert_eq!(
+                        video.framebuffer()[y * 160 + x],
+                        video.palettes.bgp[pixel],
+                        "LCDC {:02X} at ({}, {})",
+                        lcdc,
+                        x,
+                        y
+                    );
                 }
             }
         }
+    }
+
+    #[test]
+    fn bgp_change_mid_frame() {
+        let mut video = video_with_background();
+        for row in 0..32 {
+            video.write_vram(0, 0x9800 + row * 32, 1);
+        }
+
+        for ly in 0..72 {
+            video.ly = ly;
+            video.render_scanline();
+        }
 
-        result
+        // colour 3 becomes white
+        video.set_bgp(0x1B);
+
+        for ly in 72..144 {
+            video.ly = ly;
+            video.render_scanline();
+        }
+
+        let column: Vec<_> = (0..144).map(|y| video.framebuffer()[y * 160]).collect();
+        assert_eq!(column[..72], [Shade::Black; 72][..]);
+        assert_eq!(column[72..], [Shade::White; 72][..]);
     }
 
-    pub fn framebuffer(&self) -> &[Shade] {
-        &self.framebuffer
+    #[test]
+    fn lyc_interrupt_on_matching_line() {
+        for &lyc in &[5, 100, 144, 153] {
+            let mut video = Video::new();
+            video.set_lcdc(0x80);
+            video.write_stat(0x40); // LYC=LY
+            video.set_lyc(lyc);
+
+            assert_eq!(stat_interrupts(&mut video, 154), [lyc], "LYC {}", lyc);
+            assert!(!video.coincidence_flag());
+        }
+
+        // line 0 matches again after the frame wraps
+        let mut video = Video::new();
+        video.set_lcdc(0x80);
+        video.write_stat(0x40);
+        video.set_lyc(0);
+
+        assert_eq!(stat_interrupts(&mut video, 200), [0, 0]);
+    }
+
+    #[test]
+    fn lcd_restarts_from_oam_search() {
+        let mut video = Video::new();
+        video.set_lcdc(0x80);
+        video.write_stat(0x08); // HBlank
+        stat_interrupts(&mut video, 10);
+        video.step(300);
+
+        video.set_lcdc(0x00);
+        assert_eq!(video.ly, 0);
+        assert_eq!(video.read_stat() & 0x03, Mode::HBlank as u8);
+
+        // nothing moves while it's off
+        video.step(1000);
+        assert_eq!(video.ly, 0);
+
+        video.set_lcdc(0x80);
+        assert_eq!(video.read_stat() & 0x03, Mode::OAMRead as u8);
+        assert_eq!((video.mode_cycles, video.dot_in_line()), (0, 0));
+
+        // the first line has its full length, and its HBlank is a fresh rising edge
+        assert_eq!(stat_interrupts(&mut video, 1), [0]);
+        assert_eq!(video.ly, 1);
+    }
+
+    #[test]
+    fn stat_writes_only_change_interrupt_enables() {
+        let mut video = Video::new();
+        video.set_lcdc(0x80);
+        video.set_lyc(0);
+
+        // OAM search on a line matching LYC
+        let before = video.read_stat();
+        assert_eq!(before, 0x80 | 0x04 | Mode::OAMRead as u8);
+
+        video.write_stat(0xFF);
+        assert_eq!(video.read_stat(), before | 0x78);
+
+        video.write_stat(0x00);
+        assert_eq!(video.read_stat(), before);
+    }
+
+    #[test]
+    fn tile_atlas_layout() {
+        use Shade::*;
+
+        // the first row of tile 17, which is second along the second row of tiles
+        let mut video = Video::new();
+        video.write_vram(0, 0x8110, 0xF0);
+        video.write_vram(0, 0x8111, 0xCC);
+
+        let atlas = video.tile_atlas([White, LightGrey, DarkGrey, Black]);
+        assert_eq!(atlas.len(), 128 * 192);
+        assert_eq!(
+            atlas[8 * 128 + 8..][..8],
+            [Black, Black, LightGrey, LightGrey, DarkGrey, DarkGrey, White, White]
+        );
+
+        // the palette picks the shade of each colour number
+        let atlas = video.tile_atlas([Black, Black, Black, White]);
+        assert_eq!(atlas[8 * 128 + 8..][..3], [White, White, Black]);
+        assert!(atlas[..8 * 128].iter().all(|&shade| shade == Black));
+    }
+
+    #[test]
+    fn background_map_view() {
+        let mut video = video_with_background();
+        video.write_vram(0, 0x9BFF, 1); // the bottom right tile
+        video.scx = 12;
+        video.scy = 34;
+
+        let palette = [
+            Shade::White,
+            Shade::LightGrey,
+            Shade::DarkGrey,
+            Shade::Black,
+        ];
+        let map = video.render_background_map(palette);
+        assert_eq!(map.len(), 256 * 256);
+
+        // the whole map is drawn, not just what's on screen
+        let black: Vec<_> = (0..256 * 256)
+            .filter(|&i| map[i] == Shade::Black)
+            .map(|i| (i % 256, i / 256))
+            .collect();
+        let expected: Vec<_> = (0..8)
+            .flat_map(|y| (0..8).map(move |x| (x, y)))
+            .chain((248..256).flat_map(|y| (248..256).map(move |x| (x, y))))
+            .collect();
+        assert_eq!(black, expected);
+
+        assert_eq!(video.viewport(), (12, 34));
+    }
+
+    // the first four pixels of a behind priority sprite over the black tile, with LCDC bit 0 clear
+    fn sprite_over_disabled_background(cgb_mode: bool) -> Vec<Shade> {
+        let mut video = video_with_background();
+        video.set_cgb_mode(cgb_mode);
+        video.set_obp0(0xE4);
+
+        // colours 0, 1, 2, 3, 0, 1, 2, 3
+        for row in 0..8 {
+            video.write_vram(0, 0x8020 + row * 2, 0x55);
+            video.write_vram(0, 0x8021 + row * 2, 0x33);
+        }
+        video.write_oam(0xFE00, 16);
+        video.write_oam(0xFE01, 8);
+        video.write_oam(0xFE02, 2);
+        video.write_oam(0xFE03, 0x80); // behind the background
+
+        video.set_lcdc(0x92);
+        video.render_scanline();
+        video.framebuffer()[0..4].to_vec()
+    }
+
+    #[test]
+    fn background_master_priority() {
+        use Shade::*;
+
+        // DMG blanks the background
+        assert_eq!(
+            sprite_over_disabled_background(false),
+            [White, LightGrey, DarkGrey, Black]
+        );
+
+        // CGB still draws it, but the sprite goes on top despite its priority
+        assert_eq!(
+            sprite_over_disabled_background(true),
+            [Black, LightGrey, DarkGrey, Black]
+        );
+    }
+
+    #[test]
+    fn cgb_tile_attributes() {
+        let mut video = Video::new();
+        video.set_cgb_mode(true);
+        video.set_lcdc(0x91);
+
+        // tile 1 is colour 1 throughout in bank 0, but only has its top left pixel set in bank 1
+        for byte in 0..16 {
+            video.write_vram(0, 0x8010 + byte, if byte % 2 == 0 { 0xFF } else { 0x00 });
+        }
+        video.write_vram(1, 0x8010, 0x80);
+        video.write_vram(1, 0x8011, 0x80);
+
+        // palette 2, VRAM bank 1, flipped both ways
+        video.write_vram(0, 0x9800, 1);
+        video.write_vram(1, 0x9800, 0x02 | 0x08 | 0x20 | 0x40);
+
+        // colour 3 of background palette 2 is blue
+        video.write_cgb_register(0xFF68, 0x80 | 22);
+        video.write_cgb_register(0xFF69, 0x00);
+        video.write_cgb_register(0xFF69, 0x7C);
+
+        for ly in 0..8 {
+            video.ly = ly;
+            video.render_scanline();
+        }
+
+        let coloured: Vec<_> = (0..8)
+            .flat_map(|y| (0..8).map(move |x| (x, y)))
+            .filter(|&(x, y)| video.colour_framebuffer[y * 160 + x] != 0)
+            .collect();
+        assert_eq!(coloured, [(7, 7)]);
+        assert_eq!(video.colour_framebuffer[7 * 160 + 7], 0x7C00);
+    }
+
+    #[test]
+    fn tile_decoding() {
+        // the example tile from the Pan Docs
+        const BYTES: [u8; 16] = [
+            0x3C, 0x7E, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x7E, 0x5E, 0x7E, 0x0A, 0x7C, 0x56,
+            0x38, 0x7C,
+        ];
+        #[rustfmt::skip]
+        const PIXELS: [u8; 64] = [
+            0, 2, 3, 3, 3, 3, 2, 0,
+            0, 3, 0, 0, 0, 0, 3, 0,
+            0, 3, 0, 0, 0, 0, 3, 0,
+            0, 3, 0, 0, 0, 0, 3, 0,
+            0, 3, 1, 3, 3, 3, 3, 0,
+            0, 1, 1, 1, 3, 1, 3, 0,
+            0, 3, 1, 3, 1, 3, 2, 0,
+            0, 2, 3, 3, 3, 2, 0, 0,
+        ];
+
+        let mut video = Video::new();
+        for (i, &byte) in BYTES.iter().enumerate() {
+            video.write_vram(1, 0x97F0 + i as u16, byte);
+        }
+
+        assert_eq!(video.tiles[1][383].pixels[..], PIXELS[..]);
+        assert_eq!(video.tiles[0][383].pixels[..], [0; 64][..]);
+    }
+
+    #[test]
+    fn ly_counts_every_line_once() {
+        let mut video = Video::new();
+        video.set_lcdc(0x80);
+
+        // (LY, mode) each time either changes over a frame
+        let mut seen = vec![(video.ly, video.read_stat() & 0x03)];
+        for _ in 0..154 * 456 / 4 {
+            video.step(4);
+
+            let now = (video.ly, video.read_stat() & 0x03);
+            if seen.last() != Some(&now) {
+                seen.push(now);
+            }
+        }
+
+        let mut lines: Vec<u8> = seen.iter().map(|&(ly, _)| ly).collect();
+        lines.dedup();
+        assert_eq!(lines, (0..=153).chain(Some(0)).collect::<Vec<_>>());
+
+        // line 143 is drawn, VBlank starts with line 144
+        let modes = |line: u8| -> Vec<u8> {
+            seen.iter()
+                .filter(|&&(ly, _)| ly == line)
+                .map(|&(_, mode)| mode)
+                .collect()
+        };
+        assert_eq!(modes(143), [2, 3, 0]);
+        assert_eq!(modes(144), [1]);
+        assert_eq!(modes(153), [1]);
+    }
+
+    #[test]
+    fn vram_locked_from_cpu_while_drawing() {
+        let mut video = video_with_background();
+
+        while video.read_stat() & 0x03 != Mode::VRAMRead as u8 {
+            video.step(4);
+        }
+        assert_eq!(video.read_byte(0x8010), 0xFF);
+        assert_eq!(video.read_byte(0x9800), 0xFF);
+        assert_eq!(video.peek_byte(0x9800), 0x01);
+
+        // the PPU itself still sees the tile it's drawing
+        while video.read_stat() & 0x03 == Mode::VRAMRead as u8 {
+            video.step(4);
+        }
+        assert_eq!(video.framebuffer()[..8], [Shade::Black; 8]);
+        assert_eq!(video.framebuffer()[8], Shade::White);
+
+        // and the CPU can read it again once the line is done
+        assert_eq!(video.read_byte(0x9800), 0x01);
+    }
+
+    #[test]
+    fn unusual_object_palette() {
+        use Shade::*;
+
+        let mut video = video_with_background();
+        video.set_lcdc(0x93);
+
+        // colour 0 would be black if it were used, then light grey, dark grey and white
+        video.set_obp0(0x27);
+        assert_eq!(
+            video.palettes.obp0,
+            [None, Some(LightGrey), Some(DarkGrey), Some(White)]
+        );
+
+        // colours 0, 1, 2, 3, 0, 1, 2, 3 over the black background tile
+        for row in 0..8 {
+            video.write_vram(0, 0x8020 + row * 2, 0x55);
+            video.write_vram(0, 0x8021 + row * 2, 0x33);
+        }
+        video.write_oam(0xFE00, 16);
+        video.write_oam(0xFE01, 8);
+        video.write_oam(0xFE02, 2);
+
+        video.render_scanline();
+        assert_eq!(
+            video.framebuffer()[0..4],
+            [Black, LightGrey, DarkGrey, White]
+        );
+    }
+
+    #[test]
+    fn signed_tile_indices() {
+        let mut video = Video::new();
+        video.set_lcdc(0x81); // tiles from 0x8800
+        video.set_bgp(0xE4);
+
+        // tile 1 at 0x8010 is dark grey, which 0x8800 addressing never reaches
+        for row in 0..8 {
+            video.write_vram(0, 0x8010 + row * 2, 0x00);
+            video.write_vram(0, 0x8011 + row * 2, 0xFF);
+            video.write_vram(0, 0x8FF0 + row * 2, 0xFF); // tile 255, light grey
+            video.write_vram(0, 0x9010 + row * 2, 0xFF); // tile 257, black
+            video.write_vram(0, 0x9011 + row * 2, 0xFF);
+        }
+
+        // 1 is tile 257 from the 0x9000 base, and -1 is tile 255 just below it
+        video.write_vram(0, 0x9800, 0x01);
+        video.write_vram(0, 0x9801, 0xFF);
+
+        video.render_scanline();
+        assert_eq!(video.framebuffer()[..8], [Shade::Black; 8]);
+        assert_eq!(video.framebuffer()[8..16], [Shade::LightGrey; 8]);
+        assert_eq!(video.framebuffer()[16], Shade::White);
+    }
+
+    // the first row of tile 0 in each bank after writing its low byte with VBK=0 and its high
+    // byte with VBK=1
+    fn tile_rows_in_banks(cgb_mode: bool) -> ([u8; 8], [u8; 8]) {
+        let mut video = Video::new();
+        video.set_cgb_mode(cgb_mode);
+
+        video.write_cgb_register(0xFF4F, 0x00);
+        video.write_byte(0x8000, 0xFF);
+        video.write_cgb_register(0xFF4F, 0x01);
+        video.write_byte(0x8001, 0xFF);
+
+        let row = |bank: usize| {
+            let mut row = [0; 8];
+            row.copy_from_slice(&video.tiles[bank][0].pixels[..8]);
+            row
+        };
+
+        (row(0), row(1))
+    }
+
+    #[test]
+    fn tiles_decoded_per_bank() {
+        assert_eq!(tile_rows_in_banks(true), ([1; 8], [2; 8]));
+
+        // DMG only has bank 0
+        assert_eq!(tile_rows_in_banks(false), ([3; 8], [0; 8]));
     }
 }
@@ -0,0 +1,78 @@
+use crate::{Button, Console};
+use js_sys::Uint8ClampedArray;
+use wasm_bindgen::prelude::*;
+
+// wasm-bindgen generates free() to destroy the console from JS
+#[wasm_bindgen]
+pub struct WasmConsole(Console);
+
+#[wasm_bindgen]
+impl WasmConsole {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        WasmConsole(Console::new())
+    }
+
+    #[wasm_bindgen(js_name = loadRom)]
+    pub fn load_rom(&mut self, bytes: &[u8]) -> Result<(), JsValue> {
+        self.0
+            .load_rom_bytes(bytes)
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    #[wasm_bindgen(js_name = runFrame)]
+    pub fn run_frame(&mut self) -> bool {
+        self.0.run_frame()
+    }
+
+    // RGBA, ready for an ImageData
+    pub fn framebuffer(&self) -> Uint8ClampedArray {
+        Uint8ClampedArray::from(&self.0.framebuffer_rgba()[..])
+    }
+
+    // buttons are numbered as in the joypad register: Right, Left, Up, Down, A, B, Select, Start
+    #[wasm_bindgen(js_name = setButton)]
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        let button = match button {
+            0 => Button::Right,
+            1 => Button::Left,
+            2 => Button::Up,
+            3 => Button::Down,
+            4 => Button::A,
+            5 => Button::B,
+            6 => Button::Select,
+            7 => Button::Start,
+            _ => return,
+        };
+
+        self.0.set_button(button, pressed);
+    }
+}
+
+impl Default for WasmConsole {
+    fn default() -> Self {
+        WasmConsole::new()
+    }
+}
+
+// JS values can only be made inside a wasm runtime, so only the plain Rust paths are tested here
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_a_frame() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+
+        let mut console = WasmConsole::new();
+        assert!(console.load_rom(&rom).is_ok());
+
+        console.set_button(4, true);
+        console.set_button(8, true);
+        assert_eq!(console.0.joypad.buttons(), 1 << Button::A as u8);
+
+        assert!(console.run_frame());
+        assert_eq!(console.0.current_line(), 144);
+    }
+}
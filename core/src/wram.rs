@@ -0,0 +1,143 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// work RAM, on CGB 0xD000-0xDFFF can be switched between banks 1-7 with SVBK
+#[allow(clippy::upper_case_acronyms)]
+pub struct WRAM {
+    ram: [u8; 0x8000],
+    bank: u8,
+    cgb_mode: bool,
+}
+
+impl WRAM {
+    pub fn new() -> Self {
+        WRAM {
+            ram: [0; 0x8000],
+            bank: 1,
+            cgb_mode: false,
+        }
+    }
+}
+
+impl WRAM {
+    // on DMG only banks 0 and 1 exist and SVBK does nothing
+    pub fn set_cgb_mode(&mut self, cgb_mode: bool) {
+        self.cgb_mode = cgb_mode;
+    }
+
+    // 0xC000-0xDFFF, or its echo at 0xE000-0xFDFF
+    pub fn read_byte(&self, address: u16) -> u8 {
+        self.ram[self.index(address)]
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        let index = self.index(address);
+        self.ram[index] = value;
+    }
+
+    fn index(&self, address: u16) -> usize {
+        let address = usize::from(address) & 0x1FFF;
+
+        match address {
+            0x0000..=0x0FFF => address,
+            _ => usize::from(self.bank) * 0x1000 + address - 0x1000,
+        }
+    }
+
+    pub fn read_svbk(&self) -> u8 {
+        if !self.cgb_mode {
+            return 0xFF;
+        }
+
+        0xF8 | self.bank
+    }
+
+    // bank 0 is always at 0xC000, selecting it gives bank 1
+    pub fn write_svbk(&mut self, value: u8) {
+        if !self.cgb_mode {
+            return;
+        }
+
+        self.bank = match value & 0x07 {
+            0 => 1,
+            bank => bank,
+        };
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+impl Snapshot for WRAM {
+    fn save(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_u8(self.bank);
+    }
+
+    fn load(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.ram)?;
+        self.bank = match state.read_u8()? {
+            bank @ 1..=7 => bank,
+            _ => return Err(StateError::InvalidValue),
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::console;
+
+    fn state_with_bank(bank: u8) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_bytes(&[0; 0x8000]);
+        state.write_u8(bank);
+        state.into_bytes()
+    }
+
+    #[test]
+    fn load_rejects_invalid_bank() {
+        for &bank in &[0, 8, 0x40] {
+            let bytes = state_with_bank(bank);
+            let mut state = StateReader::new(&bytes).unwrap();
+
+            let mut wram = WRAM::new();
+            assert_eq!(wram.load(&mut state), Err(StateError::InvalidValue));
+        }
+    }
+
+    #[test]
+    fn load_restores_bank() {
+        let bytes = state_with_bank(7);
+        let mut state = StateReader::new(&bytes).unwrap();
+
+        let mut wram = WRAM::new();
+        wram.set_cgb_mode(true);
+        wram.load(&mut state).unwrap();
+
+        assert_eq!(wram.read_svbk(), 0xFF);
+        wram.write_byte(0xD000, 0x42);
+        assert_eq!(wram.ram[7 * 0x1000], 0x42);
+    }
+
+    #[test]
+    fn svbk_switches_banks() {
+        let mut console = console(&[0x18, 0xFE], true);
+
+        console.write(0xFF70, 2);
+        console.write(0xD000, 0x22);
+        console.write(0xFF70, 3);
+        console.write(0xD000, 0x33);
+
+        assert_eq!(console.peek(0xD000), 0x33);
+        console.write(0xFF70, 2);
+        assert_eq!(console.peek(0xFF70), 0xFA);
+        assert_eq!(console.peek(0xD000), 0x22);
+
+        // selecting bank 0 gives bank 1
+        console.write(0xFF70, 0);
+        assert_eq!(console.peek(0xFF70), 0xF9);
+    }
+}
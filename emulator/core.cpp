@@ -1,10 +1,12 @@
 // Copyright 2019 Alex Tennant
 #include "core.hpp"
 
+#include <stdexcept>
+
 extern "C" {
 void *gb_create();
 void gb_destroy(void *);
-void gb_load_rom(void *, const char *, char *);
+int gb_load_rom(void *, const char *, char *, size_t);
 void gb_run_frame(void *);
 void gb_get_frame_buffer(void *, core::Shade *);
 // void gb_get_debug_info(void *, void *);
@@ -15,9 +17,20 @@ namespace core {
 Emulator::Emulator() : emulator(gb_create(), gb_destroy) {}
 
 std::string Emulator::load_rom(const std::string &filename) {
-  char title[16];
-  gb_load_rom(emulator.get(), filename.c_str(), title);
-  return std::string(title);
+  char title[17];
+  LoadStatus status = static_cast<LoadStatus>(
+      gb_load_rom(emulator.get(), filename.c_str(), title, sizeof(title)));
+
+  switch (status) {
+    case LoadStatus::Ok:
+      return std::string(title);
+    case LoadStatus::IOError:
+      throw std::runtime_error("Unable to read " + filename);
+    case LoadStatus::UnsupportedCartridge:
+      throw std::runtime_error("Unsupported cartridge type");
+    default:
+      throw std::runtime_error("Unable to load " + filename);
+  }
 }
 
 void Emulator::run_frame() { gb_run_frame(emulator.get()); }
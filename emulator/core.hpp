@@ -14,6 +14,14 @@ enum struct Shade : uint8_t {
   Black = 3
 };
 
+enum struct LoadStatus : int {
+  Ok = 0,
+  NullPointer = 1,
+  IOError = 2,
+  UnsupportedCartridge = 3,
+  Panic = 4
+};
+
 class Emulator {
  public:
   Emulator();
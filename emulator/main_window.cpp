@@ -7,10 +7,12 @@
 #include <QtWidgets/QLabel>
 #include <QtWidgets/QMainWindow>
 #include <QtWidgets/QMenuBar>
+#include <QtWidgets/QMessageBox>
 
 #include <algorithm>
 #include <chrono>
 #include <memory>
+#include <stdexcept>
 #include <string>
 #include <vector>
 
@@ -125,7 +127,14 @@ void MainWindow::open_rom() {
       QFileDialog::getOpenFileName(this, "Open ROM", "", "Gameboy ROMs (*.gb)")
           .toStdString();
 
-  std::string title = emulator.load_rom(filename);
+  std::string title;
+  try {
+    title = emulator.load_rom(filename);
+  } catch (const std::runtime_error &error) {
+    QMessageBox::warning(this, "Open ROM", error.what());
+    return;
+  }
+
   setWindowTitle(QString::fromStdString(title));
 
   startTimer(0, Qt::TimerType::PreciseTimer);